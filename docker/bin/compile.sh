@@ -2,15 +2,17 @@
 
 set -o errexit
 
-ponyc --version
+. /usr/local/bin/protocol.sh
 
 DIR="$(mktemp -d)"
 mkdir "$DIR/main"
 cd "$DIR/main"
+
+read_header
 cat > main.pony
 
-ponyc --debug "$@"
-printf '\377' # 255 in octal
+run_compiler --debug "$@"
+if [ "$status" -ne 0 ]; then exit "$status"; fi
 
 if [ -f main.ll ]; then cat main.ll; fi
 if [ -f main.s ]; then cat main.s; fi
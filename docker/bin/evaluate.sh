@@ -2,13 +2,22 @@
 
 set -o errexit
 
-ponyc --version
+. /usr/local/bin/protocol.sh
 
 DIR="$(mktemp -d)"
 mkdir "$DIR/main"
 cd "$DIR/main"
+
+read_header
 cat > main.pony
 
-ponyc --debug --verbose=0 "$@" 2>&1
-printf '\377' # 255 in octal
-exec ./main
+run_compiler --debug --verbose=0
+if [ "$status" -ne 0 ]; then exit "$status"; fi
+
+# env NAME=value... ./main arg..., the compiler saw none of them
+set -- env
+while IFS= read -r variable; do set -- "$@" "$variable"; done < "$DIR/env"
+set -- "$@" ./main
+while IFS= read -r arg; do set -- "$@" "$arg"; done < "$DIR/args"
+run_program "$@"
+exit "$status"
@@ -0,0 +1,109 @@
+# Framing shared by evaluate.sh and compile.sh, see src/protocol.rs
+
+# Read the header of the input, up to the empty line before the code
+#
+# Writes the program's stdin to $DIR/stdin, its arguments and environment
+# variables to $DIR/args and $DIR/env, one per line.
+read_header() {
+    token=
+    : > "$DIR/stdin"
+    : > "$DIR/args"
+    : > "$DIR/env"
+    while IFS= read -r line && [ -n "$line" ]; do
+        case "${line%% *}" in
+            token) token="${line#* }" ;;
+            stdin) printf '%s' "${line#* }" | base64 -d > "$DIR/stdin" ;;
+            arg) printf '%s\n' "${line#* }" >> "$DIR/args" ;;
+            env) printf '%s\n' "${line#* }" >> "$DIR/env" ;;
+        esac
+    done
+}
+
+# Milliseconds since boot, in steps of ten
+now_ms() {
+    read -r uptime _ < /proc/uptime
+    # the fraction has two digits, keep a leading zero from reading as octal
+    echo $(( ${uptime%.*} * 1000 + (1${uptime#*.} - 100) * 10 ))
+}
+
+# Read what the sandbox's cgroup used so far into $cpu_user, $cpu_system (in
+# microseconds) and $peak_memory (in bytes), leaving them empty if unknown
+read_usage() {
+    cpu_user= cpu_system= peak_memory=
+    if [ -r /sys/fs/cgroup/cpu.stat ]; then
+        while read -r key value; do
+            case "$key" in
+                user_usec) cpu_user=$value ;;
+                system_usec) cpu_system=$value ;;
+            esac
+        done < /sys/fs/cgroup/cpu.stat
+    elif [ -r /sys/fs/cgroup/cpuacct/cpuacct.usage_user ]; then
+        cpu_user=$(( $(cat /sys/fs/cgroup/cpuacct/cpuacct.usage_user) / 1000 ))
+        cpu_system=$(( $(cat /sys/fs/cgroup/cpuacct/cpuacct.usage_sys) / 1000 ))
+    fi
+    if [ -r /sys/fs/cgroup/memory.peak ]; then
+        peak_memory=$(cat /sys/fs/cgroup/memory.peak)
+    elif [ -r /sys/fs/cgroup/memory/memory.max_usage_in_bytes ]; then
+        peak_memory=$(cat /sys/fs/cgroup/memory/memory.max_usage_in_bytes)
+    fi
+}
+
+# Start timing a phase
+#
+# The cgroup's peak memory cannot be reset from here, so it only tells what
+# the first phase used, the program's usage comes from report-exit instead.
+start_phase() {
+    start=$(now_ms)
+    read_usage
+    start_user=$cpu_user
+    start_system=$cpu_system
+}
+
+# End a phase, setting $elapsed and $usage to the frame fields of what it used
+end_phase() {
+    elapsed=$(( $(now_ms) - start ))
+    read_usage
+    usage=
+    if [ -n "$start_user" ] && [ -n "$cpu_user" ]; then
+        usage=" cpu_user_us=$(( cpu_user - start_user )) cpu_system_us=$(( cpu_system - start_system ))"
+    fi
+    if [ -n "$peak_memory" ]; then
+        usage="$usage peak_memory_bytes=$peak_memory"
+    fi
+}
+
+# Run ponyc with the given arguments and frame its output
+#
+# Sets $status to ponyc's exit status.
+run_compiler() {
+    start_phase
+    status=0
+    {
+        ponyc --version
+        ponyc "$@"
+    } > "$DIR/ponyc.out" 2> "$DIR/ponyc.err" || status=$?
+    end_phase
+    printf '%s compiler status=%d elapsed_ms=%d stdout=%d stderr=%d%s\n' \
+        "$token" "$status" "$elapsed" \
+        $(( $(wc -c < "$DIR/ponyc.out") )) $(( $(wc -c < "$DIR/ponyc.err") )) "$usage"
+    cat "$DIR/ponyc.out" "$DIR/ponyc.err"
+}
+
+# Run the given program, ending its output with how it exited and what it used
+#
+# Sets $status to the program's exit status, 128 plus the signal if one
+# terminated it, which the exit line reports as `signal` next to it.
+run_program() {
+    start=$(now_ms)
+    status=0
+    report-exit "$DIR/exit" "$@" < "$DIR/stdin" || status=$?
+    elapsed=$(( $(now_ms) - start ))
+    signal= usage=
+    if read -r ended value fields < "$DIR/exit"; then
+        case "$ended" in
+            signal) signal=" signal=$value" ;;
+        esac
+        usage=" $fields"
+    fi
+    printf '%s exit status=%d%s elapsed_ms=%d%s\n' "$token" "$status" "$signal" "$elapsed" "$usage"
+}
@@ -0,0 +1,73 @@
+/*
+ * report-exit FILE COMMAND [ARG...]
+ *
+ * Runs COMMAND and writes how it ended to FILE, `status N` if it exited and
+ * `signal N` if a signal terminated it, which a shell cannot tell apart from
+ * an exit status of 128 + N. What it used follows as exit line fields, its
+ * CPU time and peak memory, whatever cgroup setup the sandbox has. Exits
+ * like a shell would afterwards.
+ */
+#include <errno.h>
+#include <stdio.h>
+#include <sys/resource.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+static long long micros(struct timeval time)
+{
+    return (long long)time.tv_sec * 1000000 + time.tv_usec;
+}
+
+int main(int argc, char **argv)
+{
+    if (argc < 3) {
+        fprintf(stderr, "usage: report-exit FILE COMMAND [ARG...]\n");
+        return 2;
+    }
+
+    pid_t child = fork();
+    if (child < 0) {
+        perror("report-exit: fork");
+        return 126;
+    }
+    if (child == 0) {
+        execvp(argv[2], argv + 2);
+        int missing = errno == ENOENT;
+        perror(argv[2]);
+        _exit(missing ? 127 : 126);
+    }
+
+    int wstatus;
+    struct rusage usage;
+    while (wait4(child, &wstatus, 0, &usage) < 0) {
+        if (errno != EINTR) {
+            perror("report-exit: wait4");
+            return 126;
+        }
+    }
+
+    int status;
+    const char *ended;
+    int value;
+    if (WIFSIGNALED(wstatus)) {
+        status = 128 + WTERMSIG(wstatus);
+        ended = "signal";
+        value = WTERMSIG(wstatus);
+    } else {
+        status = WEXITSTATUS(wstatus);
+        ended = "status";
+        value = status;
+    }
+
+    FILE *report = fopen(argv[1], "w");
+    if (report == NULL) {
+        perror(argv[1]);
+        return status;
+    }
+    /* ru_maxrss is in KiB on Linux */
+    fprintf(report, "%s %d cpu_user_us=%lld cpu_system_us=%lld peak_memory_bytes=%lld\n",
+            ended, value, micros(usage.ru_utime), micros(usage.ru_stime),
+            (long long)usage.ru_maxrss * 1024);
+    fclose(report);
+    return status;
+}
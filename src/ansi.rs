@@ -0,0 +1,255 @@
+//! ANSI escape codes in the output of the compiler and programs
+
+use serde::Deserialize;
+use utoipa::openapi::RefOr;
+use utoipa::openapi::schema::{ObjectBuilder, OneOfBuilder, Schema, Type};
+use utoipa::{PartialSchema, ToSchema};
+
+const ESC: char = '\x1b';
+
+/// Names of the eight basic colors, as in the `ansi-*` classes of the frontend
+const COLORS: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+/// What to do with ANSI escape codes in output
+///
+/// Requests ask for it with `true` or `"ansi"`, `false` or `"none"`, or `"html"`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "ColorChoice")]
+pub enum Color {
+    /// drop them
+    None,
+    /// leave them as they are
+    #[default]
+    Ansi,
+    /// turn colors and bold text into HTML, escaping everything else
+    Html,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorChoice {
+    Enabled(bool),
+    Named(ColorName),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ColorName {
+    None,
+    Ansi,
+    Html,
+}
+
+impl From<ColorChoice> for Color {
+    fn from(choice: ColorChoice) -> Self {
+        match choice {
+            ColorChoice::Enabled(true) | ColorChoice::Named(ColorName::Ansi) => Color::Ansi,
+            ColorChoice::Enabled(false) | ColorChoice::Named(ColorName::None) => Color::None,
+            ColorChoice::Named(ColorName::Html) => Color::Html,
+        }
+    }
+}
+
+impl PartialSchema for Color {
+    fn schema() -> RefOr<Schema> {
+        OneOfBuilder::new()
+            .item(ObjectBuilder::new().schema_type(Type::Boolean))
+            .item(
+                ObjectBuilder::new()
+                    .schema_type(Type::String)
+                    .enum_values(Some(["none", "ansi", "html"])),
+            )
+            .description(Some(
+                "what to do with ANSI escape codes: `true` or `\"ansi\"` keeps them, \
+                 `false` or `\"none\"` drops them, `\"html\"` turns them into HTML",
+            ))
+            .into()
+    }
+}
+
+impl ToSchema for Color {}
+
+impl Color {
+    /// Render all of `text`
+    pub fn render(self, text: &str) -> String {
+        let mut renderer = Renderer::new(self);
+        let mut rendered = renderer.push(text);
+        rendered += &renderer.finish();
+        rendered
+    }
+}
+
+/// Renders text arriving in chunks, keeping escape codes split across chunks together
+///
+/// With [`Color::Html`], every chunk is well-formed on its own: the style is
+/// closed at its end and opened again at the start of the next one.
+#[derive(Debug)]
+pub struct Renderer {
+    color: Color,
+    /// the start of an escape code the next chunk may complete
+    incomplete: String,
+    bold: bool,
+    foreground: Option<&'static str>,
+    /// whether the tags of the style are open
+    styled: bool,
+}
+
+impl Renderer {
+    pub fn new(color: Color) -> Self {
+        Renderer {
+            color,
+            incomplete: String::new(),
+            bold: false,
+            foreground: None,
+            styled: false,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &str) -> String {
+        if self.color == Color::Ansi {
+            return chunk.to_string();
+        }
+        let text = std::mem::take(&mut self.incomplete) + chunk;
+        let mut rendered = String::new();
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find(ESC) {
+            self.text(&mut rendered, &rest[..start]);
+            match escape_len(&rest[start..]) {
+                Some(len) => {
+                    self.escape(&mut rendered, &rest[start..start + len]);
+                    rest = &rest[start + len..];
+                }
+                None => {
+                    self.incomplete = rest[start..].to_string();
+                    rest = "";
+                }
+            }
+        }
+        self.text(&mut rendered, rest);
+        rendered += &self.close();
+        rendered
+    }
+
+    /// Whatever is left once no more chunks arrive
+    pub fn finish(self) -> String {
+        match self.color {
+            // an escape code cut short is no use to anyone
+            Color::None | Color::Html => String::new(),
+            Color::Ansi => self.incomplete,
+        }
+    }
+
+    fn text(&mut self, rendered: &mut String, text: &str) {
+        if self.color != Color::Html {
+            rendered.push_str(text);
+            return;
+        }
+        if !text.is_empty() {
+            rendered.push_str(&self.open());
+        }
+        for c in text.chars() {
+            match c {
+                '&' => rendered.push_str("&amp;"),
+                '<' => rendered.push_str("&lt;"),
+                '>' => rendered.push_str("&gt;"),
+                '"' => rendered.push_str("&quot;"),
+                c => rendered.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self, rendered: &mut String, code: &str) {
+        if self.color != Color::Html {
+            return;
+        }
+        let Some(parameters) = code
+            .strip_prefix("\x1b[")
+            .and_then(|code| code.strip_suffix('m'))
+        else {
+            return;
+        };
+        rendered.push_str(&self.close());
+        // `\x1b[m` resets, but a parameter that does not parse, empty or out
+        // of range, is skipped rather than taken for one
+        let parameters = if parameters.is_empty() {
+            "0"
+        } else {
+            parameters
+        };
+        let mut parameters = parameters.split(';');
+        while let Some(parameter) = parameters.next() {
+            let Ok(parameter) = parameter.parse::<u8>() else {
+                continue;
+            };
+            match parameter {
+                0 => {
+                    self.bold = false;
+                    self.foreground = None;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                n @ 30..=37 => self.foreground = Some(COLORS[usize::from(n - 30)]),
+                n @ 90..=97 => self.foreground = Some(COLORS[usize::from(n - 90)]),
+                39 => self.foreground = None,
+                // 256 colors and true color, more than the frontend knows
+                38 | 48 => match parameters.next() {
+                    Some("5") => {
+                        parameters.next();
+                    }
+                    Some("2") => {
+                        parameters.nth(2);
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// The tags of the style, unless they are open already
+    fn open(&mut self) -> String {
+        if self.styled {
+            return String::new();
+        }
+        self.styled = true;
+        let mut tags = String::new();
+        if let Some(foreground) = self.foreground {
+            tags += &format!("<span class=\"ansi-{}\">", foreground);
+        }
+        if self.bold {
+            tags += "<strong>";
+        }
+        tags
+    }
+
+    /// The end tags of the style, if they are open
+    fn close(&mut self) -> String {
+        if !std::mem::take(&mut self.styled) {
+            return String::new();
+        }
+        let mut tags = String::new();
+        if self.bold {
+            tags += "</strong>";
+        }
+        if self.foreground.is_some() {
+            tags += "</span>";
+        }
+        tags
+    }
+}
+
+/// Length of the escape code `text` starts with, `None` if it is cut short
+fn escape_len(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().skip(1);
+    match chars.next()? {
+        // control sequences end with a byte in @ to ~
+        (_, '[') => chars
+            .find(|(_, c)| ('@'..='~').contains(c))
+            .map(|(i, c)| i + c.len_utf8()),
+        // character set designations, as in the ESC ( B that ends colors
+        (_, '(' | ')') => chars.next().map(|(i, c)| i + c.len_utf8()),
+        (i, c) => Some(i + c.len_utf8()),
+    }
+}
@@ -1,23 +1,60 @@
 use anyhow::Result;
 use axum::{
     Router,
+    extract::{DefaultBodyLimit, FromRef},
+    middleware::from_fn_with_state,
     routing::{get, post},
 };
 
-use crate::GithubClient;
-use crate::routes::{compile, create_gist, evaluate, static_css, static_html, static_js};
+use crate::client::{self, TrustedProxies};
+use crate::ratelimit::{self, Limiter};
+use crate::routes::{
+    cancel, compile, create_gist, evaluate, evaluate_batch, evaluate_stream, job, openapi,
+    static_css, static_html, static_js, stats, submit_job,
+};
+use crate::{GithubClient, Playpen};
 use axum::http::HeaderValue;
 use std::net::SocketAddr;
 use tower_http::cors::CorsLayer;
 
-/// serve the api
-pub async fn serve(addr: SocketAddr, github_client: GithubClient) -> Result<()> {
+/// shared state of all routes
+#[derive(Clone)]
+pub(crate) struct AppState {
+    github: GithubClient,
+    playpen: Playpen,
+}
+
+impl FromRef<AppState> for GithubClient {
+    fn from_ref(state: &AppState) -> Self {
+        state.github.clone()
+    }
+}
+
+impl FromRef<AppState> for Playpen {
+    fn from_ref(state: &AppState) -> Self {
+        state.playpen.clone()
+    }
+}
+
+/// serve the api, running code in the sandbox of the given `playpen`
+pub async fn serve(addr: SocketAddr, github_client: GithubClient, playpen: Playpen) -> Result<()> {
     let layer: CorsLayer = CorsLayer::new().allow_origin(
         "https://tutorial.ponylang.io"
             .parse::<HeaderValue>()
             .unwrap(),
     );
 
+    let rate_limit = &playpen.config().rate_limit;
+    // streamed evaluations count towards the same limit, as do the legacy routes
+    let evaluate_limiter = Limiter::new(rate_limit.evaluate);
+    let evaluate_limit = from_fn_with_state(evaluate_limiter.clone(), ratelimit::limit);
+    let compile_limit = from_fn_with_state(Limiter::new(rate_limit.compile), ratelimit::limit);
+    let gist_limit = from_fn_with_state(Limiter::new(rate_limit.gist), ratelimit::limit);
+    let jobs_limit = from_fn_with_state(Limiter::new(rate_limit.jobs), ratelimit::limit);
+    let batch_limit = from_fn_with_state(Limiter::new(rate_limit.batch), ratelimit::limit);
+    let proxies = TrustedProxies::new(playpen.config().server.trusted_proxies.clone());
+    let body_limit = DefaultBodyLimit::max(playpen.config().server.max_body_bytes);
+
     let static_routes = Router::new()
         .route(
             "/web.css",
@@ -32,16 +69,60 @@ pub async fn serve(addr: SocketAddr, github_client: GithubClient) -> Result<()>
             get(|| async { static_js(include_bytes!("../static/mode-pony.js")) }),
         );
     let router = Router::new()
-        .route("/evaluate.json", post(evaluate))
+        .route(
+            "/api/v1/evaluate",
+            post(evaluate).route_layer(evaluate_limit.clone()),
+        )
+        .route(
+            "/api/v1/evaluate/stream",
+            post(evaluate_stream).route_layer(evaluate_limit.clone()),
+        )
+        .route("/api/v1/cancel", post(cancel))
+        // the routes before the versioned API, kept for older clients
+        .route(
+            "/evaluate.json",
+            post(evaluate).route_layer(evaluate_limit.clone()),
+        )
+        .route(
+            "/evaluate.sse",
+            post(evaluate_stream).route_layer(evaluate_limit),
+        )
+        .route("/cancel.json", post(cancel))
         .layer(layer) // applies to every route() call before on `router`
         .route(
             "/",
             get(|| async { static_html(include_bytes!("../static/web.html")) }),
         )
-        .route("/compile.json", post(compile))
-        .route("/gist.json", post(create_gist))
-        .with_state(github_client)
-        .nest("/static", static_routes);
+        .route(
+            "/api/v1/compile",
+            post(compile).route_layer(compile_limit.clone()),
+        )
+        .route(
+            "/api/v1/gists",
+            post(create_gist).route_layer(gist_limit.clone()),
+        )
+        .route(
+            "/api/v1/batch",
+            post(evaluate_batch).route_layer(batch_limit),
+        )
+        .route("/api/v1/jobs", post(submit_job).route_layer(jobs_limit))
+        .route("/api/v1/jobs/{id}", get(job))
+        .route("/api/v1/stats", get(stats))
+        .route("/api/v1/openapi.json", get(openapi))
+        .route("/compile.json", post(compile).route_layer(compile_limit))
+        .route("/gist.json", post(create_gist).route_layer(gist_limit))
+        .route("/stats.json", get(stats))
+        .with_state(AppState {
+            github: github_client,
+            playpen,
+        })
+        .nest("/static", static_routes)
+        .layer(body_limit)
+        .layer(from_fn_with_state(proxies, client::resolve));
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    Ok(axum::serve(listener, router).await?)
+    Ok(axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?)
 }
@@ -1,8 +1,9 @@
 use anyhow::Result;
 
 use pony_playground::api;
-use pony_playground::{GithubClient, init_github_client};
+use pony_playground::{Config, DockerSandbox, Engine, GithubClient, Playpen, init_github_client};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::process::Command;
 
 #[tokio::main]
@@ -20,8 +21,18 @@ async fn main() -> Result<()> {
     };
     let github_client: GithubClient = init_github_client(token)?;
 
+    let config = match std::env::var("PLAYPEN_CONFIG") {
+        Ok(path) => Config::load(Path::new(&path))?,
+        Err(_) => Config::default(),
+    };
+    let sandbox = DockerSandbox::with_pool(Engine::from_env(), config.pool.clone());
+    sandbox.check_runtimes(&config).await?;
+    for engine in sandbox.engines(&config) {
+        sandbox.spawn_reaper_on(engine, config.reaper.clone());
+    }
+
     // TODO: determine either by env var or command line argument
     let addr = SocketAddr::from(([127, 0, 0, 1], 8000));
     log::info!("Listening on  {addr}...");
-    api::serve(addr, github_client).await
+    api::serve(addr, github_client, Playpen::with_config(sandbox, config)).await
 }
@@ -1,12 +1,24 @@
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use utoipa::ToSchema;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum Branch {
     Release,
     Regions,
 }
 
 impl Branch {
+    pub const ALL: [Branch; 2] = [Branch::Release, Branch::Regions];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Branch::Release => "release",
+            Branch::Regions => "regions",
+        }
+    }
+
     pub fn image(&self) -> &'static str {
         match *self {
             Branch::Release => "ponylang-playpen:latest",
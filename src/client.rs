@@ -0,0 +1,95 @@
+//! Who is making a request
+//!
+//! Behind a reverse proxy, every request comes from the proxy's address. If
+//! the proxy is listed in `trusted_proxies`, the client address is taken from
+//! the `X-Forwarded-For` header it adds instead.
+
+use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use axum::http::HeaderMap;
+use axum::http::request::Parts;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Address of the client making a request, used to share the sandboxes fairly
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(client) = parts.extensions.get::<ClientIp>() {
+            return Ok(*client);
+        }
+        Ok(ClientIp(peer(&parts.extensions)))
+    }
+}
+
+/// Addresses of reverse proxies whose `X-Forwarded-For` header is believed
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrustedProxies(Arc<Vec<IpAddr>>);
+
+impl TrustedProxies {
+    pub(crate) fn new(proxies: Vec<IpAddr>) -> TrustedProxies {
+        TrustedProxies(Arc::new(proxies))
+    }
+
+    fn trusts(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+
+    /// The client behind a request that came in from `peer`
+    ///
+    /// Proxies append the address they got a request from, so the header is
+    /// read from the right, skipping our own proxies. Anything left of the
+    /// first address not belonging to them was sent by the client and cannot
+    /// be trusted.
+    fn client(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(&peer) {
+            return peer;
+        }
+        let forwarded: Vec<IpAddr> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .map(|ip| ip.parse().ok())
+            .collect::<Option<_>>()
+            // a garbled header, rather not guess
+            .unwrap_or_default();
+        let mut client = peer;
+        for ip in forwarded.into_iter().rev() {
+            client = ip;
+            if !self.trusts(&ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Middleware figuring out the [`ClientIp`] of every request once, for the
+/// extractor and the rate limits to pick up
+pub(crate) async fn resolve(
+    State(proxies): State<TrustedProxies>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let client = proxies.client(peer(request.extensions()), request.headers());
+    request.extensions_mut().insert(ClientIp(client));
+    next.run(request).await
+}
+
+fn peer(extensions: &axum::http::Extensions) -> IpAddr {
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+        // not served with connect info, as in tests calling the router directly
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
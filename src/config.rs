@@ -0,0 +1,203 @@
+//! Configuration of the playground, read from a TOML file
+//!
+//! Every setting has a default, so an empty file (or none at all) will do.
+//! The defaults add limits the playground did not have before, among them a
+//! CPU cap of one CPU, 128 KiB of code, 64 KiB of stdin and 1 MiB of output
+//! per stream. See `playpen.example.toml` for all of them.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use crate::Branch;
+use crate::jobs::JobsConfig;
+use crate::pool::PoolConfig;
+use crate::queue::QueueConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::reaper::ReaperConfig;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub rate_limit: RateLimitConfig,
+    pub queue: QueueConfig,
+    pub pool: PoolConfig,
+    pub reaper: ReaperConfig,
+    pub jobs: JobsConfig,
+    pub batch: BatchConfig,
+    pub branches: HashMap<Branch, BranchConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Settings for `branch`, the defaults if it has no section of its own
+    pub fn branch(&self, branch: Branch) -> BranchConfig {
+        self.branches.get(&branch).cloned().unwrap_or_default()
+    }
+}
+
+/// Settings of the HTTP server
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// reverse proxies whose `X-Forwarded-For` header tells the client address
+    pub trusted_proxies: Vec<IpAddr>,
+    /// size of a request body, larger ones are answered with 413
+    pub max_body_bytes: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            trusted_proxies: vec![],
+            max_body_bytes: 2 * 1024 * 1024,
+        }
+    }
+}
+
+/// Settings of batch evaluations
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    /// snippets a single batch may have
+    pub max_snippets: usize,
+    /// snippets of a batch evaluated at the same time
+    pub parallelism: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_snippets: 200,
+            parallelism: 4,
+        }
+    }
+}
+
+/// Settings of a single branch
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BranchConfig {
+    pub profile: Profile,
+    pub runtime: Runtime,
+    pub limits: Limits,
+}
+
+/// Container engine and OCI runtime the sandboxes of a branch run on
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Runtime {
+    /// socket of a Docker compatible engine, e.g. `/run/podman/podman.sock`,
+    /// the one `DOCKER_HOST` points at if unset
+    pub socket: Option<PathBuf>,
+    /// OCI runtime registered with the engine, e.g. `runsc` for gVisor,
+    /// the engine's default runtime if unset
+    pub oci_runtime: Option<String>,
+}
+
+/// How tightly sandboxes are locked down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// no capabilities and no network, but a writable root filesystem
+    #[default]
+    Standard,
+    /// on top of that a read-only root filesystem, size-capped tmpfs mounts
+    /// for the workspace and `/tmp`, the project's seccomp profile and an
+    /// unprivileged user
+    Hardened,
+}
+
+/// Resources a single sandbox may use
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// memory limit in MiB
+    pub memory_mb: u64,
+    /// swap on top of the memory limit in MiB, docker's default (as much as memory) if unset
+    pub swap_mb: Option<u64>,
+    /// length of a CPU scheduling period in microseconds
+    pub cpu_period_us: u64,
+    /// CPU time per period in microseconds, 0 means unlimited
+    pub cpu_quota_us: u64,
+    /// relative weight against other containers competing for CPU
+    pub cpu_shares: u64,
+    /// maximum number of processes and threads
+    pub pids: u64,
+    /// wall-clock time after which the sandbox is killed
+    #[serde(rename = "timeout_secs", with = "secs")]
+    #[schema(value_type = u64, default = 10)]
+    pub timeout: Duration,
+    pub ulimits: Vec<Ulimit>,
+    /// length of the code to compile
+    pub max_code_bytes: usize,
+    /// input a program may be given on stdin
+    pub max_stdin_bytes: usize,
+    /// output the program may write to stdout, the sandbox is killed once it
+    /// writes more, what the compiler writes does not count
+    pub max_stdout_bytes: usize,
+    /// output kept from stderr, the sandbox is killed once it writes more
+    pub max_stderr_bytes: usize,
+    /// assembly or LLVM IR kept from compiling, the sandbox is killed once
+    /// there is more
+    pub max_compile_output_bytes: usize,
+    /// size of the workspace tmpfs in MiB, hardened profile only
+    pub workspace_mb: u64,
+    /// size of the `/tmp` tmpfs in MiB, hardened profile only
+    pub tmp_mb: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            memory_mb: 1024,
+            swap_mb: None,
+            cpu_period_us: 100_000,
+            cpu_quota_us: 100_000,
+            cpu_shares: 1024,
+            pids: 20,
+            timeout: Duration::from_secs(10),
+            ulimits: vec![],
+            max_code_bytes: 128 * 1024,
+            max_stdin_bytes: 64 * 1024,
+            max_stdout_bytes: 1024 * 1024,
+            max_stderr_bytes: 1024 * 1024,
+            max_compile_output_bytes: 16 * 1024 * 1024,
+            workspace_mb: 64,
+            tmp_mb: 16,
+        }
+    }
+}
+
+/// A resource limit as set by `ulimit`, e.g. `nofile` or `fsize`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Ulimit {
+    pub name: String,
+    pub soft: i64,
+    pub hard: i64,
+}
+
+/// (De)serialize a [`Duration`] as whole seconds
+pub(crate) mod secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+}
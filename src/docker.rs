@@ -1,184 +1,527 @@
 use anyhow::Result;
-use std::os::unix::prelude::ExitStatusExt;
-use std::process::{ExitStatus, Output, Stdio};
-use std::time::{Duration, Instant};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::process::Command;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::io::{AsyncWriteExt, ReadHalf};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
 use tokio::time;
 
-#[derive(Debug)]
-pub(crate) enum ChildResult {
-    ExitCode(i32),
-    #[allow(dead_code)] // we don't wanna let go of that signal
-    Signal(i32),
-    TimedOut,
+use crate::Branch;
+use crate::config::{Config, Limits, Profile, Runtime};
+use crate::engine::{
+    AttachedStream, ContainerConfig, Demux, Engine, HostConfig, StreamKind, Ulimit,
+};
+use crate::pool::{Pool, PoolConfig};
+use crate::protocol::{Decoder, ScriptInput};
+use crate::reaper::{self, ReaperConfig};
+use crate::sandbox::{Chunk, PoolStats, RunRequest, RunResult, Sandbox, Stream};
+
+/// Label every playground container carries
+pub(crate) const LABEL: &str = "io.ponylang.playpen";
+/// Label naming the playground instance that created a container
+pub(crate) const INSTANCE_LABEL: &str = "io.ponylang.playpen.instance";
+const BRANCH_LABEL: &str = "io.ponylang.playpen.branch";
+/// Label naming the request a container was created for, `pooled` for pooled containers
+const REQUEST_LABEL: &str = "io.ponylang.playpen.request";
+
+/// Where programs are compiled and run in the hardened profile, the only
+/// place besides `/tmp` they can write to
+const WORKSPACE: &str = "/workspace";
+/// `nobody`, which needs no entry in the image's `/etc/passwd`
+const HARDENED_USER: &str = "65534:65534";
+/// Syscalls available in the hardened profile
+const SECCOMP_PROFILE: &str = include_str!("../docker/seccomp.json");
+
+/// Sandbox running every request in a fresh docker container
+#[derive(Clone)]
+pub struct DockerSandbox {
+    engine: Engine,
+    instance: String,
+    pool: Option<Pool>,
 }
 
-impl From<ExitStatus> for ChildResult {
-    fn from(es: ExitStatus) -> Self {
-        es.code()
-            .map(ChildResult::ExitCode)
-            .or_else(|| es.signal().map(ChildResult::Signal))
-            .unwrap() // it should either be an exitcode or a signal
+impl DockerSandbox {
+    pub fn new(engine: Engine) -> DockerSandbox {
+        DockerSandbox {
+            engine,
+            instance: uuid::Uuid::new_v4().simple().to_string(),
+            pool: None,
+        }
     }
-}
 
-#[derive(Debug)]
-pub struct RunResult {
-    pub(crate) result: ChildResult,
-    pub(crate) stdout: Vec<u8>,
-    pub(crate) stderr: Vec<u8>,
-}
+    /// Take containers from a pool of already created ones instead of creating them on demand
+    pub fn with_pool(engine: Engine, config: PoolConfig) -> DockerSandbox {
+        let mut sandbox = Self::new(engine);
+        if config.size > 0 {
+            sandbox.pool = Some(Pool::new(
+                sandbox.engine.clone(),
+                sandbox.instance.clone(),
+                config,
+            ));
+        }
+        sandbox
+    }
 
-impl RunResult {
-    fn new(status: ExitStatus, stdout: Vec<u8>, stderr: Vec<u8>) -> Self {
-        Self {
-            result: status.into(),
-            stdout,
-            stderr,
+    /// Id of this playground instance, set as label on all of its containers
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// Remove the containers left behind by earlier instances, then keep
+    /// removing containers that outlive `config.max_lifetime`
+    pub fn spawn_reaper(&self, config: ReaperConfig) -> JoinHandle<()> {
+        self.spawn_reaper_on(self.engine.clone(), config)
+    }
+
+    /// Like [`DockerSandbox::spawn_reaper`], for the containers on another engine
+    pub fn spawn_reaper_on(&self, engine: Engine, config: ReaperConfig) -> JoinHandle<()> {
+        reaper::spawn(engine, self.instance.clone(), config)
+    }
+
+    /// All engines the branches of `config` run their sandboxes on
+    pub fn engines(&self, config: &Config) -> Vec<Engine> {
+        let mut engines = vec![self.engine.clone()];
+        for branch in Branch::ALL {
+            if let Some(socket) = config.branch(branch).runtime.socket
+                && !engines.iter().any(|engine| engine.socket() == socket)
+            {
+                engines.push(Engine::new(socket));
+            }
         }
+        engines
     }
 
-    fn timed_out(stdout: Vec<u8>, stderr: Vec<u8>) -> Self {
-        Self {
-            result: ChildResult::TimedOut,
-            stdout,
-            stderr,
+    /// Make sure the engines and OCI runtimes of all branches are there,
+    /// rather than failing on the first request for a branch
+    pub async fn check_runtimes(&self, config: &Config) -> Result<()> {
+        let mut problems = vec![];
+        for branch in Branch::ALL {
+            let runtime = config.branch(branch).runtime;
+            let engine = match &runtime.socket {
+                Some(socket) => Engine::new(socket),
+                None => self.engine.clone(),
+            };
+            let info = match engine.info().await {
+                Ok(info) => info,
+                Err(e) => {
+                    problems.push(format!(
+                        "branch {}: {}. Is the engine running{}?",
+                        branch.name(),
+                        e,
+                        if runtime.socket.is_some() {
+                            ", for Podman its API service (systemctl enable --now podman.socket)"
+                        } else {
+                            ", and does DOCKER_HOST point at it"
+                        }
+                    ));
+                    continue;
+                }
+            };
+            let Some(oci_runtime) = &runtime.oci_runtime else {
+                continue;
+            };
+            if !info.runtimes.contains_key(oci_runtime) {
+                let mut known: Vec<_> = info.runtimes.keys().map(String::as_str).collect();
+                known.sort();
+                problems.push(format!(
+                    "branch {}: the engine at {} does not know the OCI runtime `{}`, only {}. \
+                     Install it and register it with the engine, \
+                     e.g. in the \"runtimes\" section of /etc/docker/daemon.json",
+                    branch.name(),
+                    engine.socket().display(),
+                    oci_runtime,
+                    known.join(", ")
+                ));
+                continue;
+            }
+            info!(
+                "branch {} runs on {} {} at {} with runtime {}",
+                branch.name(),
+                info.name,
+                info.server_version,
+                engine.socket().display(),
+                oci_runtime
+            );
         }
+        if !problems.is_empty() {
+            anyhow::bail!(
+                "container runtimes are not available:\n  {}",
+                problems.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for DockerSandbox {
+    fn default() -> Self {
+        Self::new(Engine::from_env())
+    }
+}
+
+impl Sandbox for DockerSandbox {
+    fn run(&self, request: RunRequest) -> BoxFuture<'_, Result<RunResult>> {
+        Box::pin(async move {
+            let start = Instant::now();
+            let spec = ContainerSpec::from(&request);
+            let container = match &self.pool {
+                Some(pool) => pool.checkout(&spec, &request.id).await?,
+                None => Container::new(&self.engine, &spec, &self.instance, &request.id).await?,
+            };
+            let result = container
+                .run(&request.input, &request.limits, request.output.clone())
+                .await?;
+            Ok(result.timed(start.elapsed()))
+        })
     }
 
-    pub(crate) fn stdout(&self) -> &[u8] {
-        self.stdout.as_slice()
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        self.pool.as_ref().map(Pool::stats).unwrap_or_default()
     }
+}
+
+/// Everything that goes into creating a container
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct ContainerSpec {
+    pub(crate) branch: Branch,
+    pub(crate) image: String,
+    pub(crate) cmd: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) profile: Profile,
+    pub(crate) runtime: Runtime,
+    pub(crate) limits: Limits,
+}
 
-    pub(crate) fn stderr(&self) -> &[u8] {
-        self.stderr.as_slice()
+impl ContainerSpec {
+    /// The engine to create the container with, `default` unless the runtime names another socket
+    pub(crate) fn engine(&self, default: &Engine) -> Engine {
+        match &self.runtime.socket {
+            Some(socket) => Engine::new(socket),
+            None => default.clone(),
+        }
     }
+}
 
-    pub(crate) fn success(&self) -> bool {
-        matches!(self.result, ChildResult::ExitCode(0))
+impl From<&RunRequest> for ContainerSpec {
+    fn from(request: &RunRequest) -> Self {
+        ContainerSpec {
+            branch: request.branch,
+            image: request.branch.image().to_string(),
+            cmd: request.cmd.clone(),
+            args: request.args.clone(),
+            env: request.env.clone(),
+            profile: request.profile,
+            runtime: request.runtime.clone(),
+            limits: request.limits.clone(),
+        }
     }
 }
 
+/// Lock `config` down to the hardened profile
+fn harden(config: &mut ContainerConfig, limits: &Limits) {
+    config.user = Some(HARDENED_USER.to_string());
+    // the scripts create their files in a `mktemp -d` directory
+    config.env.push(format!("TMPDIR={}", WORKSPACE));
+    config.env.push(format!("HOME={}", WORKSPACE));
+    let host_config = &mut config.host_config;
+    host_config.readonly_rootfs = true;
+    host_config.tmpfs = HashMap::from([
+        (
+            WORKSPACE.to_string(),
+            format!("rw,nosuid,nodev,size={}m,mode=1777", limits.workspace_mb),
+        ),
+        (
+            "/tmp".to_string(),
+            format!("rw,noexec,nosuid,nodev,size={}m,mode=1777", limits.tmp_mb),
+        ),
+    ]);
+    host_config
+        .security_opt
+        .push(format!("seccomp={}", SECCOMP_PROFILE));
+}
+
 pub struct Container {
+    engine: Engine,
     id: String,
 }
 
 impl Container {
-    pub async fn new(
-        cmd: &str,
-        args: &[String],
-        env: &[(String, String)],
-        name: &str,
+    pub(crate) async fn new(
+        engine: &Engine,
+        spec: &ContainerSpec,
+        instance: &str,
+        request: &str,
     ) -> Result<Container> {
-        let out = run(Command::new("docker")
-            .arg("create")
-            .arg("--cap-drop=ALL")
-            .arg("--memory=1024m")
-            .arg("--net=none")
-            .arg("--pids-limit=20")
-            .arg("--security-opt=no-new-privileges")
-            .arg("--interactive")
-            .args(
-                env.iter()
-                    .map(|(k, v)| format!("--env={}={}", k, v))
-                    .collect::<Vec<_>>(),
-            )
-            .arg(name)
-            .arg(cmd)
-            .stderr(Stdio::inherit())
-            .args(args))
-        .await?;
-        let stdout = String::from_utf8_lossy(&out.stdout);
+        const MIB: i64 = 1024 * 1024;
+        let engine = &spec.engine(engine);
+        let limits = &spec.limits;
+        let mut config = ContainerConfig {
+            image: spec.image.clone(),
+            cmd: std::iter::once(spec.cmd.clone())
+                .chain(spec.args.iter().cloned())
+                .collect(),
+            env: spec
+                .env
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect(),
+            labels: HashMap::from([
+                (LABEL.to_string(), "1".to_string()),
+                (INSTANCE_LABEL.to_string(), instance.to_string()),
+                (BRANCH_LABEL.to_string(), spec.branch.name().to_string()),
+                (REQUEST_LABEL.to_string(), request.to_string()),
+            ]),
+            attach_stdin: true,
+            attach_stdout: true,
+            attach_stderr: true,
+            open_stdin: true,
+            stdin_once: true,
+            network_disabled: true,
+            host_config: HostConfig {
+                cap_drop: vec!["ALL".to_string()],
+                memory: limits.memory_mb as i64 * MIB,
+                memory_swap: limits
+                    .swap_mb
+                    .map(|swap| (limits.memory_mb + swap) as i64 * MIB),
+                cpu_period: limits.cpu_period_us as i64,
+                cpu_quota: limits.cpu_quota_us as i64,
+                cpu_shares: limits.cpu_shares as i64,
+                pids_limit: limits.pids as i64,
+                ulimits: limits
+                    .ulimits
+                    .iter()
+                    .map(|ulimit| Ulimit {
+                        name: ulimit.name.clone(),
+                        soft: ulimit.soft,
+                        hard: ulimit.hard,
+                    })
+                    .collect(),
+                network_mode: "none".to_string(),
+                security_opt: vec!["no-new-privileges".to_string()],
+                runtime: spec.runtime.oci_runtime.clone(),
+                ..HostConfig::default()
+            },
+            ..ContainerConfig::default()
+        };
+        if spec.profile == Profile::Hardened {
+            harden(&mut config, limits);
+        }
+        let id = engine.create_container(&config).await?;
+        debug!("created container {} for {}", id, request);
         Ok(Container {
-            id: stdout.trim().to_string(),
+            engine: engine.clone(),
+            id,
         })
     }
 
-    pub async fn run(&self, input: &[u8], timeout: Duration) -> Result<RunResult> {
-        let mut cmd = Command::new("docker");
-        cmd.arg("start")
-            .arg("--attach")
-            .arg("--interactive")
-            .arg(&self.id)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .kill_on_drop(true); // ensure we kill the process when leavin this func if it timed out
-        debug!("attaching with {:?}", cmd);
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Feed `input` to the container and collect its output, passing it on to
+    /// `sink` as it arrives
+    pub async fn run(
+        &self,
+        input: &[u8],
+        limits: &Limits,
+        sink: Option<UnboundedSender<Chunk>>,
+    ) -> Result<RunResult> {
+        let stream = self.engine.attach(&self.id).await?;
+        let (reader, mut writer) = tokio::io::split(stream);
+        let token = ScriptInput::decode(input).map(|input| input.token);
+        let mut output = Output::new(reader, limits, token.as_deref(), sink);
+        self.engine.start(&self.id).await?;
+        debug!("started {}, now feeding input", self.id);
         let start = Instant::now();
-        let mut cmd = cmd.spawn()?;
-        cmd.stdin.take().unwrap().write_all(input).await?;
-        debug!("input written, now waiting");
-
-        let mut stdout_buf = Vec::new();
-        let mut stderr_buf = Vec::new();
-        let mut child_stdout = cmd.stdout.take().unwrap();
-        let mut child_stderr = cmd.stderr.take().unwrap();
-
-        match time::timeout(
-            timeout,
-            futures::future::join3(
-                cmd.wait(),
-                child_stdout.read_to_end(&mut stdout_buf),
-                child_stderr.read_to_end(&mut stderr_buf),
-            ),
-        )
-        .await
-        {
+
+        let feed = async {
+            // the container might exit before reading all of its input, that's fine
+            if let Err(e) = writer.write_all(input).await {
+                debug!("writing input to {} failed: {}", self.id, e);
+            }
+            if let Err(e) = writer.shutdown().await {
+                debug!("closing input of {} failed: {}", self.id, e);
+            }
+        };
+        let finished = time::timeout(limits.timeout, async {
+            let ((), collected) =
+                futures::future::join(feed, self.collect_output(&mut output)).await;
+            collected?;
+            Ok::<_, anyhow::Error>(self.engine.wait(&self.id).await?)
+        })
+        .await;
+
+        let result = match finished {
             Err(_) => {
-                // timeout
                 debug!("timed out: {:?}", start.elapsed());
-                // kill docker container
-                run(Command::new("docker").arg("kill").arg(&self.id)).await?;
-                // try waiting again
-                let (_status, stderr, stdout) = futures::future::join3(
-                    cmd.wait(),
-                    child_stdout.read_to_end(&mut stdout_buf),
-                    child_stderr.read_to_end(&mut stderr_buf),
-                )
-                .await;
-                {
-                    Ok(RunResult::timed_out(
-                        stdout.map(|_| stdout_buf)?,
-                        stderr.map(|_| stderr_buf)?,
-                    ))
-                }
+                self.kill().await;
+                self.collect_output(&mut output).await?;
+                RunResult::timed_out(output.stdout.bytes, output.stderr.bytes)
+                    .with_timeline(output.timeline)
             }
-            Ok((status, stdout, stderr)) => {
+            Ok(status) => {
+                let status = status?;
                 debug!("timing: {:?}", start.elapsed());
-                Ok(RunResult::new(
-                    status?,
-                    stdout.map(|_| stdout_buf)?,
-                    stderr.map(|_| stderr_buf)?,
-                ))
+                let (stdout, stderr) = (output.stdout.bytes, output.stderr.bytes);
+                // like a shell, docker reports death by signal n as exit code 128 + n,
+                // the exit line of the scripts tells better where there is one
+                match status - 128 {
+                    signal @ 1..=64 => RunResult::signaled(signal as i32, stdout, stderr),
+                    _ => RunResult::exited(status as i32, stdout, stderr),
+                }
+                .with_timeline(output.timeline)
             }
+        };
+        Ok(result.written(
+            (output.stdout.total, output.stdout.truncated),
+            (output.stderr.total, output.stderr.truncated),
+        ))
+    }
+
+    /// Read all output, killing the container once it exceeds an output limit
+    async fn collect_output(&self, output: &mut Output) -> std::io::Result<()> {
+        while output.collect().await? {
+            debug!("output limit exceeded, killing {}", self.id);
+            self.kill().await;
+        }
+        Ok(())
+    }
+
+    async fn kill(&self) {
+        if let Err(e) = self.engine.kill(&self.id).await {
+            // it might have exited on its own in the meantime
+            debug!("killing {} failed: {}", self.id, e);
         }
     }
 }
 
 impl Drop for Container {
     fn drop(&mut self) {
-        let rt = tokio::runtime::Handle::current();
+        let Ok(rt) = tokio::runtime::Handle::try_current() else {
+            warn!("no runtime left to remove container {}", self.id);
+            return;
+        };
+        let engine = self.engine.clone();
         let id = self.id.clone();
-        rt.spawn(
-            Command::new("docker")
-                .arg("rm")
-                .arg("--force")
-                .arg(&id)
-                .output(),
-        );
+        rt.spawn(async move {
+            if let Err(e) = engine.remove(&id).await {
+                warn!("failed to remove container {}: {}", id, e);
+            }
+        });
     }
 }
 
-async fn run(cmd: &mut Command) -> Result<Output> {
-    debug!("spawning: {:?}", cmd);
-    let start = Instant::now();
-    let out = cmd.output().await?;
-    debug!("done in: {:?}", start.elapsed());
-    debug!("output: {:?}", out);
-    if !out.status.success() {
-        anyhow::bail!("process failed: {:?}\n{:?}", cmd, out);
+/// Output collected from an attached container
+struct Output {
+    demux: Demux<ReadHalf<AttachedStream>>,
+    stdout: Capture,
+    stderr: Capture,
+    truncated: bool,
+    sink: Option<UnboundedSender<Chunk>>,
+    /// when the container was attached to, right before it started
+    attached: Instant,
+    /// the kept output of both streams, in the order it arrived
+    timeline: Vec<Chunk>,
+}
+
+/// One output stream, kept up to a limit
+///
+/// On a stream the scripts frame, only the program's share counts towards
+/// the limit. The frames, and what ponyc wrote inside them, are always kept.
+struct Capture {
+    bytes: Vec<u8>,
+    limit: usize,
+    /// tells the program's share of a framed stream apart
+    decoder: Option<Decoder>,
+    /// the program's share of `bytes`
+    kept: usize,
+    /// everything the program wrote, including what did not fit
+    total: u64,
+    truncated: bool,
+}
+
+impl Capture {
+    fn new(limit: usize, decoder: Option<Decoder>) -> Capture {
+        Capture {
+            bytes: Vec::new(),
+            limit,
+            decoder,
+            kept: 0,
+            total: 0,
+            truncated: false,
+        }
+    }
+
+    /// Keep as much of `chunk` as fits, returns how much did
+    fn push(&mut self, chunk: &[u8]) -> usize {
+        let (written, exit_line) = match &mut self.decoder {
+            Some(decoder) => decoder.measure(chunk),
+            None => (chunk.len(), None),
+        };
+        self.total += written as u64;
+        let fits = self.limit.saturating_sub(self.kept).min(written);
+        self.kept += fits;
+        // the program's output ends where the exit line starts, which is cut
+        // off along with what did not fit
+        let kept = match written - fits {
+            0 => chunk.len(),
+            over => exit_line.unwrap_or(chunk.len()).saturating_sub(over),
+        };
+        self.truncated |= kept < chunk.len();
+        self.bytes.extend_from_slice(&chunk[..kept]);
+        kept
+    }
+}
+
+impl Output {
+    /// `token` is the one the scripts frame stdout with, if they do
+    fn new(
+        reader: ReadHalf<AttachedStream>,
+        limits: &Limits,
+        token: Option<&str>,
+        sink: Option<UnboundedSender<Chunk>>,
+    ) -> Output {
+        Output {
+            demux: Demux::new(reader),
+            stdout: Capture::new(limits.max_stdout_bytes, token.map(Decoder::new)),
+            stderr: Capture::new(limits.max_stderr_bytes, None),
+            truncated: false,
+            sink,
+            attached: Instant::now(),
+            timeline: vec![],
+        }
+    }
+
+    /// Read output until the container closes its end
+    ///
+    /// Stops early and returns `true` the first time a stream exceeds its limit.
+    async fn collect(&mut self) -> std::io::Result<bool> {
+        while let Some((kind, mut chunk)) = self.demux.next().await? {
+            let (stream, kept) = match kind {
+                StreamKind::Stdout => (Stream::Stdout, self.stdout.push(&chunk)),
+                StreamKind::Stderr => (Stream::Stderr, self.stderr.push(&chunk)),
+            };
+            let exceeded = kept < chunk.len();
+            if kept > 0 {
+                chunk.truncate(kept);
+                let chunk = Chunk {
+                    stream,
+                    offset: self.attached.elapsed(),
+                    bytes: chunk,
+                };
+                if let Some(sink) = &self.sink {
+                    // nobody listening any more is no reason to stop the run
+                    let _ = sink.send(chunk.clone());
+                }
+                self.timeline.push(chunk);
+            }
+            if exceeded && !self.truncated {
+                self.truncated = true;
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
-    Ok(out)
 }
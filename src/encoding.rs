@@ -0,0 +1,67 @@
+//! How the bytes programs write end up in JSON strings
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// How output is put into responses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// as UTF-8, replacing invalid bytes with U+FFFD
+    #[default]
+    Utf8,
+    /// base64 of the bytes as they are
+    Base64,
+    /// as [`Encoding::Utf8`], along with where the invalid bytes were
+    Utf8Ranges,
+}
+
+/// A range of bytes, `end` excluded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ByteRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Output put into a string
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Encoded {
+    pub text: String,
+    /// whether invalid bytes had to be replaced
+    pub lossy: bool,
+    /// where the replaced bytes were, if asked for with [`Encoding::Utf8Ranges`]
+    pub invalid: Option<Vec<ByteRange>>,
+}
+
+impl Encoding {
+    pub fn encode(self, bytes: &[u8]) -> Encoded {
+        if self == Encoding::Base64 {
+            return Encoded {
+                text: BASE64.encode(bytes),
+                lossy: false,
+                invalid: None,
+            };
+        }
+        let mut text = String::with_capacity(bytes.len());
+        let mut invalid = vec![];
+        let mut offset = 0;
+        for chunk in bytes.utf8_chunks() {
+            text.push_str(chunk.valid());
+            offset += chunk.valid().len();
+            if !chunk.invalid().is_empty() {
+                text.push(char::REPLACEMENT_CHARACTER);
+                invalid.push(ByteRange {
+                    start: offset,
+                    end: offset + chunk.invalid().len(),
+                });
+                offset += chunk.invalid().len();
+            }
+        }
+        Encoded {
+            text,
+            lossy: !invalid.is_empty(),
+            invalid: (self == Encoding::Utf8Ranges).then_some(invalid),
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! Client for the Docker Engine API, spoken over its unix socket
+//!
+//! Only covers the calls needed to run a single container: create, attach,
+//! start, wait, kill and remove, plus listing containers, looking up
+//! image ids and the runtimes the daemon knows about. Podman serves a compatible API on its own
+//! socket, so pointing `DOCKER_HOST` at it works just as well.
+
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::client::conn::http1::{self, SendRequest};
+use hyper::header::{CONNECTION, CONTENT_TYPE, HOST, UPGRADE};
+use hyper::upgrade::Upgraded;
+use hyper::{Method, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UnixStream;
+
+/// Where docker listens unless told otherwise
+pub const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// API version we speak, supported by docker >= 20.10 and podman >= 3
+const API_VERSION: &str = "v1.41";
+
+#[derive(Debug, thiserror::Error)]
+pub enum EngineError {
+    #[error("cannot connect to docker at {}: {source}", path.display())]
+    Connect {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("http error talking to docker: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("docker responded to {endpoint} with {status}: {message}")]
+    Api {
+        endpoint: String,
+        status: StatusCode,
+        message: String,
+    },
+    #[error("invalid response from docker to {endpoint}: {source}")]
+    InvalidResponse {
+        endpoint: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("i/o error on attached container stream: {0}")]
+    Stream(#[from] io::Error),
+}
+
+impl EngineError {
+    /// The HTTP status docker responded with, if it got that far
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            EngineError::Api { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Whether the container or image in question does not exist
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(StatusCode::NOT_FOUND)
+    }
+}
+
+type EngineResult<T> = Result<T, EngineError>;
+
+/// Body of `POST /containers/create`
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    pub image: String,
+    pub cmd: Vec<String>,
+    pub env: Vec<String>,
+    /// `user`, `uid` or `uid:gid` to run as, the image's default if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub labels: HashMap<String, String>,
+    pub attach_stdin: bool,
+    pub attach_stdout: bool,
+    pub attach_stderr: bool,
+    pub open_stdin: bool,
+    pub stdin_once: bool,
+    pub network_disabled: bool,
+    pub host_config: HostConfig,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HostConfig {
+    pub cap_drop: Vec<String>,
+    pub memory: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_swap: Option<i64>,
+    pub cpu_period: i64,
+    pub cpu_quota: i64,
+    pub cpu_shares: i64,
+    pub pids_limit: i64,
+    pub ulimits: Vec<Ulimit>,
+    pub network_mode: String,
+    pub security_opt: Vec<String>,
+    pub readonly_rootfs: bool,
+    /// OCI runtime to run the container with, the daemon's default if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<String>,
+    /// tmpfs mounts, from path in the container to mount options
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub tmpfs: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Ulimit {
+    pub name: String,
+    pub soft: i64,
+    pub hard: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Created {
+    id: String,
+}
+
+/// Entry of `GET /containers/json`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerSummary {
+    pub id: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// unix timestamp of the container's creation
+    pub created: i64,
+    /// `created`, `running`, `exited` and so on
+    pub state: String,
+}
+
+/// Parts of `GET /containers/{id}/json` we care about
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerDetails {
+    pub state: ContainerState,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerState {
+    /// when the container was started last, the year 1 if it never was
+    pub started_at: DateTime<Utc>,
+}
+
+/// Parts of `GET /info` we care about
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Info {
+    /// e.g. `docker-desktop`, the host name for podman
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub server_version: String,
+    /// OCI runtimes containers can be created with, by name
+    #[serde(default)]
+    pub runtimes: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub default_runtime: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Image {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Exited {
+    status_code: i64,
+}
+
+#[derive(Deserialize)]
+struct ErrorMessage {
+    message: String,
+}
+
+/// The raw, bidirectional stream of an attached container
+pub type AttachedStream = TokioIo<Upgraded>;
+
+/// Handle to a docker (or podman) daemon listening on a unix socket
+#[derive(Clone, Debug)]
+pub struct Engine {
+    socket: PathBuf,
+}
+
+impl Engine {
+    pub fn new(socket: impl Into<PathBuf>) -> Engine {
+        Engine {
+            socket: socket.into(),
+        }
+    }
+
+    /// Use the socket from `DOCKER_HOST` if it is a `unix://` one, the default socket otherwise
+    pub fn from_env() -> Engine {
+        match std::env::var("DOCKER_HOST") {
+            Ok(host) => match host.strip_prefix("unix://") {
+                Some(path) => Engine::new(path),
+                None => {
+                    warn!(
+                        "DOCKER_HOST={} is not a unix socket, using {}",
+                        host, DEFAULT_SOCKET
+                    );
+                    Engine::new(DEFAULT_SOCKET)
+                }
+            },
+            Err(_) => Engine::new(DEFAULT_SOCKET),
+        }
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// System-wide information about the daemon
+    pub async fn info(&self) -> EngineResult<Info> {
+        self.call_json(Method::GET, "/info", None).await
+    }
+
+    /// Create a container and return its id
+    pub async fn create_container(&self, config: &ContainerConfig) -> EngineResult<String> {
+        let body = serde_json::to_vec(config).expect("container config is serializable");
+        let created: Created = self
+            .call_json(Method::POST, "/containers/create", Some(body))
+            .await?;
+        Ok(created.id)
+    }
+
+    /// All containers, running or not, that carry the given label
+    pub async fn list_containers(&self, label: &str) -> EngineResult<Vec<ContainerSummary>> {
+        let filters = serde_json::json!({ "label": [label] }).to_string();
+        let path = format!(
+            "/containers/json?all=1&filters={}",
+            url::form_urlencoded::byte_serialize(filters.as_bytes()).collect::<String>()
+        );
+        self.call_json(Method::GET, &path, None).await
+    }
+
+    pub async fn inspect(&self, id: &str) -> EngineResult<ContainerDetails> {
+        self.call_json(Method::GET, &format!("/containers/{}/json", id), None)
+            .await
+    }
+
+    /// Id of the image currently tagged `name`
+    pub async fn image_id(&self, name: &str) -> EngineResult<String> {
+        let image: Image = self
+            .call_json(Method::GET, &format!("/images/{}/json", name), None)
+            .await?;
+        Ok(image.id)
+    }
+
+    /// Attach to stdin, stdout and stderr of a container that has not been started yet
+    pub async fn attach(&self, id: &str) -> EngineResult<AttachedStream> {
+        let path = format!(
+            "/containers/{}/attach?stream=1&stdin=1&stdout=1&stderr=1",
+            id
+        );
+        let mut sender = self.connect().await?;
+        let req = request(Method::POST, &path)
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "tcp")
+            .body(Full::default())
+            .expect("attach request is valid");
+        let res = sender.send_request(req).await?;
+        if res.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return Err(api_error(&path, res).await);
+        }
+        Ok(TokioIo::new(hyper::upgrade::on(res).await?))
+    }
+
+    pub async fn start(&self, id: &str) -> EngineResult<()> {
+        self.call(Method::POST, &format!("/containers/{}/start", id), None)
+            .await
+            .map(drop)
+    }
+
+    /// Block until the container stopped and return its exit code
+    pub async fn wait(&self, id: &str) -> EngineResult<i64> {
+        let exited: Exited = self
+            .call_json(Method::POST, &format!("/containers/{}/wait", id), None)
+            .await?;
+        Ok(exited.status_code)
+    }
+
+    pub async fn kill(&self, id: &str) -> EngineResult<()> {
+        self.call(Method::POST, &format!("/containers/{}/kill", id), None)
+            .await
+            .map(drop)
+    }
+
+    /// Remove the container, killing it first if it is still running
+    pub async fn remove(&self, id: &str) -> EngineResult<()> {
+        self.call(Method::DELETE, &format!("/containers/{}?force=1", id), None)
+            .await
+            .map(drop)
+    }
+
+    async fn connect(&self) -> EngineResult<SendRequest<Full<Bytes>>> {
+        let stream =
+            UnixStream::connect(&self.socket)
+                .await
+                .map_err(|source| EngineError::Connect {
+                    path: self.socket.clone(),
+                    source,
+                })?;
+        let (sender, conn) = http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.with_upgrades().await {
+                debug!("docker connection failed: {}", e);
+            }
+        });
+        Ok(sender)
+    }
+
+    async fn call(&self, method: Method, path: &str, body: Option<Vec<u8>>) -> EngineResult<Bytes> {
+        debug!("docker: {} {}", method, path);
+        let mut sender = self.connect().await?;
+        let mut req = request(method, path);
+        if body.is_some() {
+            req = req.header(CONTENT_TYPE, "application/json");
+        }
+        let req = req
+            .body(Full::new(body.map(Bytes::from).unwrap_or_default()))
+            .expect("docker request is valid");
+        let res = sender.send_request(req).await?;
+        if !res.status().is_success() {
+            return Err(api_error(path, res).await);
+        }
+        Ok(res.into_body().collect().await?.to_bytes())
+    }
+
+    async fn call_json<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> EngineResult<T> {
+        let bytes = self.call(method, path, body).await?;
+        serde_json::from_slice(&bytes).map_err(|source| EngineError::InvalidResponse {
+            endpoint: path.to_string(),
+            source,
+        })
+    }
+}
+
+fn request(method: Method, path: &str) -> hyper::http::request::Builder {
+    Request::builder()
+        .method(method)
+        .uri(format!("/{}{}", API_VERSION, path))
+        .header(HOST, "docker")
+}
+
+async fn api_error(path: &str, res: hyper::Response<hyper::body::Incoming>) -> EngineError {
+    let status = res.status();
+    let message = match res.into_body().collect().await {
+        Ok(body) => {
+            let body = body.to_bytes();
+            serde_json::from_slice::<ErrorMessage>(&body)
+                .map(|e| e.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).trim().to_string())
+        }
+        Err(e) => e.to_string(),
+    };
+    EngineError::Api {
+        endpoint: path.to_string(),
+        status,
+        message,
+    }
+}
+
+/// Which stream a chunk of container output was written to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    Stdout,
+    Stderr,
+}
+
+/// Splits the multiplexed output docker sends on attached streams of containers without a tty
+///
+/// Every frame starts with an 8 byte header: the stream type, three bytes of
+/// padding and the big endian length of the payload that follows.
+pub struct Demux<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Demux<R> {
+    pub fn new(reader: R) -> Demux<R> {
+        Demux {
+            reader,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Next chunk of output, `None` once the container closed its output
+    ///
+    /// This is cancel safe, partially read frames are kept around for the next call.
+    pub async fn next(&mut self) -> io::Result<Option<(StreamKind, Vec<u8>)>> {
+        loop {
+            if self.buf.len() >= 8 {
+                let len = u32::from_be_bytes([self.buf[4], self.buf[5], self.buf[6], self.buf[7]])
+                    as usize;
+                if self.buf.len() >= 8 + len {
+                    let kind = self.buf[0];
+                    let payload = self.buf[8..8 + len].to_vec();
+                    self.buf.drain(..8 + len);
+                    match kind {
+                        1 => return Ok(Some((StreamKind::Stdout, payload))),
+                        2 => return Ok(Some((StreamKind::Stderr, payload))),
+                        // stdin is never echoed back without a tty, skip it anyways
+                        0 => continue,
+                        other => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("unknown stream type {} in attached output", other),
+                            ));
+                        }
+                    }
+                }
+            }
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "attached output ended in the middle of a frame",
+                    ))
+                };
+            }
+        }
+    }
+}
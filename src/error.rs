@@ -0,0 +1,210 @@
+//! Errors of the API and how clients are told about them
+//!
+//! Every error is answered with a [problem details](https://www.rfc-editor.org/rfc/rfc9457)
+//! body. Next to the standard members, it carries a machine-readable `code`,
+//! and, for older clients, the `detail` again as `error`.
+
+use crate::{Cancelled, EngineError, InvalidProgram, QueueFull};
+use axum::{
+    Json,
+    body::Body,
+    http::{
+        HeaderValue, Response, StatusCode,
+        header::{CONTENT_TYPE, RETRY_AFTER},
+    },
+    response::IntoResponse,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+static APPLICATION_PROBLEM_JSON: HeaderValue = HeaderValue::from_static("application/problem+json");
+
+/// A problem details body
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct Problem {
+    /// always `about:blank`, the `code` tells problems apart
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: Option<&'static str>,
+    status: u16,
+    detail: String,
+    /// machine-readable kind of the problem, e.g. `queue_full`
+    code: &'static str,
+    /// the `detail` again, for older clients
+    error: String,
+    /// the field with an invalid value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'static str>,
+    /// the values the field may have
+    #[serde(skip_serializing_if = "Option::is_none")]
+    valid_values: Option<Vec<&'static str>>,
+    /// seconds to wait before trying again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+/// Why a request failed
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ApiError {
+    /// a field of the request has a value the API does not know
+    #[error("`{value}` is not a valid {field}, use one of: {}", valid.join(", "))]
+    InvalidValue {
+        field: &'static str,
+        value: String,
+        valid: Vec<&'static str>,
+    },
+    /// a URL in the request that cannot be used
+    #[error("`{field}` is not a valid URL: {reason}")]
+    InvalidUrl {
+        field: &'static str,
+        reason: &'static str,
+    },
+    #[error("the request body is larger than {limit} bytes")]
+    BodyTooLarge { limit: usize },
+    /// a body that is no JSON, or not of the shape the route takes
+    #[error("{detail}")]
+    InvalidBody { status: StatusCode, detail: String },
+    #[error(transparent)]
+    InvalidProgram(#[from] InvalidProgram),
+    #[error(transparent)]
+    QueueFull(QueueFull),
+    #[error(transparent)]
+    Cancelled(Cancelled),
+    /// the client made more requests than the rate limit allows
+    #[error("too many requests, retry in {retry_after}s")]
+    RateLimited { retry_after: u64 },
+    /// a batch with more snippets than it may have
+    #[error("a batch may have at most {limit} snippets, not {count}")]
+    TooManySnippets { count: usize, limit: usize },
+    #[error("{0}")]
+    NotFound(String),
+    /// docker cannot be reached at all
+    #[error("the sandbox is unavailable")]
+    SandboxUnavailable(#[source] anyhow::Error),
+    #[error("the sandbox failed to run the program")]
+    Sandbox(#[source] anyhow::Error),
+    #[error("GitHub could not be talked to")]
+    Github(#[source] anyhow::Error),
+    /// the compiler's output could not be highlighted
+    #[error("the output could not be highlighted")]
+    Highlight(#[source] anyhow::Error),
+}
+
+impl ApiError {
+    /// Tell apart why a run failed
+    pub(crate) fn from_run(e: anyhow::Error) -> ApiError {
+        let e = match e.downcast::<InvalidProgram>() {
+            Ok(invalid) => return ApiError::InvalidProgram(invalid),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<QueueFull>() {
+            Ok(full) => return ApiError::QueueFull(full),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<Cancelled>() {
+            Ok(cancelled) => return ApiError::Cancelled(cancelled),
+            Err(e) => e,
+        };
+        match e.downcast_ref::<EngineError>() {
+            Some(EngineError::Connect { .. }) => ApiError::SandboxUnavailable(e),
+            _ => ApiError::Sandbox(e),
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidValue { .. }
+            | ApiError::InvalidUrl { .. }
+            | ApiError::InvalidProgram(_) => StatusCode::BAD_REQUEST,
+            ApiError::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::InvalidBody { status, .. } => *status,
+            ApiError::TooManySnippets { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Cancelled(_) => StatusCode::CONFLICT,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::QueueFull(_) | ApiError::SandboxUnavailable(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ApiError::Sandbox(_) | ApiError::Github(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Highlight(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable kind of the error
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidValue { .. } => "invalid_value",
+            ApiError::InvalidUrl { .. } => "invalid_url",
+            ApiError::BodyTooLarge { .. } => "body_too_large",
+            ApiError::InvalidBody { .. } => "invalid_body",
+            ApiError::InvalidProgram(_) => "invalid_program",
+            ApiError::QueueFull(_) => "queue_full",
+            ApiError::Cancelled(_) => "cancelled",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::TooManySnippets { .. } => "too_many_snippets",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::SandboxUnavailable(_) => "sandbox_unavailable",
+            ApiError::Sandbox(_) => "sandbox_failed",
+            ApiError::Github(_) => "github_failed",
+            ApiError::Highlight(_) => "highlight_failed",
+        }
+    }
+
+    /// The problem details body
+    pub(crate) fn problem(&self) -> Problem {
+        let status = self.status();
+        let detail = self.to_string();
+        let mut problem = Problem {
+            kind: "about:blank",
+            title: status.canonical_reason(),
+            status: status.as_u16(),
+            detail: detail.clone(),
+            code: self.code(),
+            error: detail,
+            field: None,
+            valid_values: None,
+            retry_after: None,
+        };
+        match self {
+            ApiError::InvalidValue { field, valid, .. } => {
+                problem.field = Some(field);
+                problem.valid_values = Some(valid.clone());
+            }
+            ApiError::InvalidUrl { field, .. } => problem.field = Some(field),
+            ApiError::QueueFull(full) => problem.retry_after = Some(full.retry_after.as_secs()),
+            ApiError::RateLimited { retry_after } => problem.retry_after = Some(*retry_after),
+            _ => {}
+        }
+        problem
+    }
+
+    /// Log what went wrong on our side, `context` saying what was being done
+    pub(crate) fn log(&self, context: &str) {
+        match self {
+            ApiError::QueueFull(full) => warn!("{}: {}", context, full),
+            ApiError::SandboxUnavailable(e)
+            | ApiError::Sandbox(e)
+            | ApiError::Github(e)
+            | ApiError::Highlight(e) => error!("{}: {:#}", context, e),
+            _ => {}
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response<Body> {
+        let mut response = (self.status(), Json(self.problem())).into_response();
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, APPLICATION_PROBLEM_JSON.clone());
+        match &self {
+            ApiError::QueueFull(full) => {
+                headers.insert(RETRY_AFTER, full.retry_after.as_secs().into());
+            }
+            ApiError::RateLimited { retry_after } => {
+                headers.insert(RETRY_AFTER, (*retry_after).into());
+            }
+            _ => {}
+        }
+        response
+    }
+}
@@ -0,0 +1,169 @@
+//! Runs in the background, for clients that rather poll for the result than wait for it
+//!
+//! A submitted job runs like any other request, in the same queue, and is kept
+//! in a [`JobStore`] while it runs and for `retention_secs` after it finished.
+//! The playground keeps them in a [`MemoryStore`] unless it is given another.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+use crate::runs;
+
+/// Settings of the jobs API
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// how long the result of a finished job is kept
+    #[serde(rename = "retention_secs", with = "crate::config::secs")]
+    pub retention: Duration,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            retention: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// What a job does
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Evaluate,
+    Compile,
+}
+
+/// Where a job is at
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum JobState {
+    /// waiting in the queue or running
+    Running,
+    /// done, with the response the synchronous route would have answered with
+    Succeeded {
+        #[schema(value_type = Object)]
+        result: Value,
+    },
+    /// failed, with the problem details the synchronous route would have answered with
+    Failed {
+        #[schema(value_type = Object)]
+        error: Value,
+    },
+}
+
+/// A job and where it is at
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct Job {
+    pub id: String,
+    pub kind: JobKind,
+    #[serde(flatten)]
+    pub state: JobState,
+    /// seconds since the Unix epoch
+    pub submitted_at: u64,
+    /// seconds since the Unix epoch, none while running
+    pub finished_at: Option<u64>,
+    /// when the job is forgotten, in seconds since the Unix epoch, none while running
+    pub expires_at: Option<u64>,
+}
+
+/// Where jobs are kept
+pub trait JobStore: Send + Sync {
+    /// Keep `job`, replacing the one with the same id
+    fn put(&self, job: Job);
+
+    fn get(&self, id: &str) -> Option<Job>;
+
+    /// Forget the jobs that expired at or before `now`, in seconds since the Unix epoch
+    fn remove_expired(&self, now: u64);
+}
+
+/// Keeps jobs in memory, they are gone once the playground stops
+#[derive(Default)]
+pub struct MemoryStore {
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl JobStore for MemoryStore {
+    fn put(&self, job: Job) {
+        self.jobs.lock().unwrap().insert(job.id.clone(), job);
+    }
+
+    fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    fn remove_expired(&self, now: u64) {
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|_, job| job.expires_at.is_none_or(|expires| expires > now));
+    }
+}
+
+/// Jobs submitted to a playpen
+#[derive(Clone)]
+pub struct Jobs {
+    config: JobsConfig,
+    store: Arc<dyn JobStore>,
+}
+
+impl Jobs {
+    pub fn new(config: JobsConfig, store: impl JobStore + 'static) -> Jobs {
+        Jobs {
+            config,
+            store: Arc::new(store),
+        }
+    }
+
+    /// Run `job` in the background, it resolves to the result or the problem
+    /// that ended it
+    pub fn submit(
+        &self,
+        kind: JobKind,
+        job: impl Future<Output = Result<Value, Value>> + Send + 'static,
+    ) -> Job {
+        let submitted_at = now();
+        self.store.remove_expired(submitted_at);
+        let submitted = Job {
+            id: runs::new_id(),
+            kind,
+            state: JobState::Running,
+            submitted_at,
+            finished_at: None,
+            expires_at: None,
+        };
+        self.store.put(submitted.clone());
+        let jobs = self.clone();
+        let mut finished = submitted.clone();
+        tokio::spawn(async move {
+            finished.state = match job.await {
+                Ok(result) => JobState::Succeeded { result },
+                Err(error) => JobState::Failed { error },
+            };
+            let finished_at = now();
+            finished.finished_at = Some(finished_at);
+            finished.expires_at = Some(finished_at + jobs.config.retention.as_secs());
+            jobs.store.put(finished);
+        });
+        submitted
+    }
+
+    /// Job `id`, unless there never was one or it expired
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.store.remove_expired(now());
+        self.store.get(id)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -3,70 +3,415 @@ extern crate log;
 extern crate libc;
 extern crate wait_timeout;
 
-use anyhow::Result;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use anyhow::{Context, Result, bail};
+use futures::StreamExt;
+use serde::Serialize;
+use std::future::Future;
+use std::process::Stdio;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use utoipa::ToSchema;
 
+use crate::queue::Executor;
+use crate::runs::Runs;
+
+pub use ansi::{Color, Renderer};
 pub use branches::Branch;
-use docker::{Container, RunResult};
+pub use config::{
+    BatchConfig, BranchConfig, Config, Limits, Profile, Runtime, ServerConfig, Ulimit,
+};
+pub use docker::DockerSandbox;
+pub use encoding::{ByteRange, Encoded, Encoding};
+pub use engine::{Engine, EngineError, Info};
+pub use jobs::{Job, JobKind, JobState, JobStore, Jobs, JobsConfig, MemoryStore};
+pub use openapi::ApiDoc;
+pub use pool::PoolConfig;
+pub use program::{InvalidProgram, Program};
+pub use protocol::{Compilation, Decoder, Event, Exit, ScriptInput, Timings, Transcript, Usage};
+pub use queue::{QueueConfig, QueueFull, QueueStats};
+pub use ratelimit::{RateLimit, RateLimitConfig};
+pub use reaper::ReaperConfig;
+pub use runs::Cancelled;
+pub use sandbox::{
+    Chunk, FakeSandbox, Outcome, Phase, PoolStats, RunRequest, RunResult, Sandbox, Stream,
+    signal_name,
+};
 
+mod ansi;
 pub mod api;
 mod branches;
+mod client;
+mod config;
 mod docker;
+mod encoding;
+mod engine;
+mod error;
 pub(crate) mod github;
+mod jobs;
+mod openapi;
+mod pool;
+mod program;
+mod protocol;
+mod queue;
+mod ratelimit;
+mod reaper;
 pub(crate) mod routes;
+pub(crate) mod runs;
+mod sandbox;
 
 pub use github::Client as GithubClient;
 pub use github::init_client as init_github_client;
 
-pub struct Playpen;
+/// Runtime statistics of a playpen
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Stats {
+    pub pools: Vec<PoolStats>,
+    pub queue: QueueStats,
+}
+
+/// Result of compiling and running a program
+#[derive(Debug)]
+pub struct Evaluation {
+    /// the id the run could have been cancelled by
+    pub id: String,
+    /// the whole run, its stdout holding the framed output of the scripts
+    pub result: RunResult,
+    /// what the scripts reported about the compiler and the program
+    pub transcript: Transcript,
+    pub outcome: Outcome,
+    /// the arguments the program was run with
+    pub args: Vec<String>,
+    /// the environment variables the program was run with
+    pub env: Vec<(String, String)>,
+    /// the program's stdout and stderr, in the order they arrived
+    pub timeline: Vec<Chunk>,
+}
+
+impl Evaluation {
+    /// Everything the compiler wrote, empty if it did not finish
+    pub fn compiler(&self) -> String {
+        self.transcript
+            .compiler
+            .as_ref()
+            .map(Compilation::output)
+            .unwrap_or_default()
+    }
+
+    /// What the program wrote to stdout
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.transcript.stdout).into_owned()
+    }
+
+    /// How much the program wrote to stdout, kept or not
+    pub fn stdout_bytes(&self) -> u64 {
+        if self.result.stdout_truncated() {
+            self.result.stdout_bytes()
+        } else {
+            self.transcript.stdout.len() as u64
+        }
+    }
+}
+
+/// The program's part of the timeline of a run, without what the scripts framed it with
+fn program_timeline(result: &RunResult, token: &str) -> Vec<Chunk> {
+    let mut decoder = Decoder::new(token);
+    let mut timeline = vec![];
+    let stdout = |events: Vec<Event>, offset| {
+        events.into_iter().filter_map(move |event| match event {
+            Event::Stdout(bytes) => Some(Chunk {
+                stream: Stream::Stdout,
+                offset,
+                bytes,
+            }),
+            _ => None,
+        })
+    };
+    for chunk in result.timeline() {
+        match chunk.stream {
+            Stream::Stdout => timeline.extend(stdout(decoder.feed(&chunk.bytes), chunk.offset)),
+            Stream::Stderr => timeline.push(chunk.clone()),
+        }
+    }
+    // whatever the decoder held back arrived with the last chunk
+    let last = result
+        .timeline()
+        .last()
+        .map(|chunk| chunk.offset)
+        .unwrap_or_default();
+    timeline.extend(stdout(decoder.finish().1, last));
+    timeline
+}
+
+#[derive(Clone)]
+pub struct Playpen {
+    sandbox: Arc<dyn Sandbox>,
+    executor: Arc<Executor>,
+    config: Arc<Config>,
+    runs: Arc<Runs>,
+    jobs: Jobs,
+}
 
 impl Playpen {
-    pub fn new() -> Playpen {
-        Playpen
+    pub fn new(sandbox: impl Sandbox + 'static) -> Playpen {
+        Self::with_config(sandbox, Config::default())
+    }
+
+    pub fn with_config(sandbox: impl Sandbox + 'static, config: Config) -> Playpen {
+        Playpen {
+            sandbox: Arc::new(sandbox),
+            executor: Executor::new(config.queue.clone()),
+            jobs: Jobs::new(config.jobs.clone(), MemoryStore::default()),
+            config: Arc::new(config),
+            runs: Arc::default(),
+        }
+    }
+
+    /// Keep jobs in `store` instead of in memory
+    pub fn with_job_store(mut self, store: impl JobStore + 'static) -> Playpen {
+        self.jobs = Jobs::new(self.config.jobs.clone(), store);
+        self
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Limits every sandbox on `branch` runs with
+    pub fn limits(&self, branch: Branch) -> Limits {
+        self.config.branch(branch).limits
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            pools: self.sandbox.pool_stats(),
+            queue: self.executor.stats(),
+        }
+    }
+
+    /// Jobs run in the background
+    pub fn jobs(&self) -> &Jobs {
+        &self.jobs
+    }
+
+    /// Cancel evaluation `id`, waiting or running, on behalf of `client`,
+    /// returns whether `client` had such an evaluation left to cancel
+    pub fn cancel(&self, id: &str, client: &str) -> bool {
+        self.runs.cancel(id, client)
+    }
+
+    /// Run `cmd` as run `id` with `limits` once a slot is free, `client`
+    /// identifies who asked for the run to queue it fairly, `queued` gets its
+    /// position while it waits, `output` gets its output as it arrives
+    #[allow(clippy::too_many_arguments)]
     async fn exec(
+        &self,
+        id: String,
+        client: &str,
         branch: Branch,
         cmd: &str,
         args: Vec<String>,
-        input: String,
+        input: &ScriptInput,
+        limits: Limits,
+        queued: Option<UnboundedSender<usize>>,
+        output: Option<UnboundedSender<Chunk>>,
     ) -> Result<RunResult> {
-        let container = Container::new(cmd, &args, &[], branch.image()).await?;
-        container.run(input.as_bytes(), Duration::new(10, 0)).await
+        let branch_config = self.config.branch(branch);
+        let _permit = self.executor.acquire(client, &id, queued).await?;
+        self.sandbox
+            .run(RunRequest {
+                id,
+                branch,
+                cmd: cmd.to_string(),
+                args,
+                env: vec![],
+                input: input.encode(),
+                profile: branch_config.profile,
+                runtime: branch_config.runtime,
+                limits,
+                output,
+            })
+            .await
+    }
+
+    pub async fn evaluate(
+        &self,
+        client: &str,
+        branch: Branch,
+        program: impl Into<Program>,
+    ) -> Result<Evaluation> {
+        let id = runs::new_id();
+        self.runs
+            .run(
+                &id,
+                client,
+                self.run_evaluation(&id, client, branch, program.into(), None),
+            )
+            .await
     }
 
-    fn parse_output(raw: &[u8]) -> (String, String) {
-        let mut split = raw.splitn(2, |b| *b == b'\xff');
-        let compiler = String::from_utf8_lossy(split.next().unwrap_or(&[])).into_owned();
-        let output = String::from_utf8_lossy(split.next().unwrap_or(&[])).into_owned();
+    /// Evaluate all of `programs`, `parallelism` of the `[batch]` section at
+    /// a time, answering in the order of `programs`
+    pub async fn evaluate_batch(
+        &self,
+        client: &str,
+        branch: Branch,
+        programs: Vec<Program>,
+    ) -> Vec<Result<Evaluation>> {
+        futures::stream::iter(programs)
+            .map(|program| self.evaluate(client, branch, program))
+            .buffered(self.config.batch.parallelism.max(1))
+            .collect()
+            .await
+    }
 
-        (compiler, output)
+    /// Like [`Playpen::evaluate`], as run `id` to cancel it by, sending
+    /// `events` what the scripts report as they do
+    ///
+    /// The run can be cancelled as soon as this returns, before the evaluation
+    /// is awaited.
+    pub fn evaluate_streaming(
+        &self,
+        id: &str,
+        client: &str,
+        branch: Branch,
+        program: impl Into<Program>,
+        events: UnboundedSender<Event>,
+    ) -> impl Future<Output = Result<Evaluation>> {
+        let registered = self.runs.register(id, client);
+        registered.run(self.run_evaluation(id, client, branch, program.into(), Some(events)))
     }
 
-    pub async fn evaluate(branch: Branch, code: String) -> Result<(RunResult, String, String)> {
-        let result = Self::exec(branch, "/usr/local/bin/evaluate.sh", vec![], code).await?;
-        let (compiler, output) = Self::parse_output(result.stdout());
-        Ok((result, compiler, output))
+    async fn run_evaluation(
+        &self,
+        id: &str,
+        client: &str,
+        branch: Branch,
+        program: Program,
+        events: Option<UnboundedSender<Event>>,
+    ) -> Result<Evaluation> {
+        program.validate(&self.limits(branch))?;
+        let input = ScriptInput {
+            stdin: program.stdin,
+            args: program.args,
+            env: program.env,
+            ..ScriptInput::new(program.code)
+        };
+        let cmd = "/usr/local/bin/evaluate.sh";
+        let result = match events {
+            None => {
+                self.exec(
+                    id.to_string(),
+                    client,
+                    branch,
+                    cmd,
+                    vec![],
+                    &input,
+                    self.limits(branch),
+                    None,
+                    None,
+                )
+                .await?
+            }
+            Some(events) => {
+                let (queued, mut positions) = mpsc::unbounded_channel();
+                let announce = async {
+                    while let Some(position) = positions.recv().await {
+                        let _ = events.send(Event::Queued(position));
+                    }
+                };
+                let (output, mut chunks) = mpsc::unbounded_channel::<Chunk>();
+                let decode = async {
+                    let mut decoder = Decoder::new(&input.token);
+                    while let Some(chunk) = chunks.recv().await {
+                        let decoded = match chunk.stream {
+                            Stream::Stdout => decoder.feed(&chunk.bytes),
+                            Stream::Stderr => vec![Event::Stderr(chunk.bytes)],
+                        };
+                        for event in decoded {
+                            let _ = events.send(event);
+                        }
+                    }
+                    for event in decoder.finish().1 {
+                        let _ = events.send(event);
+                    }
+                };
+                let run = self.exec(
+                    id.to_string(),
+                    client,
+                    branch,
+                    cmd,
+                    vec![],
+                    &input,
+                    self.limits(branch),
+                    Some(queued),
+                    Some(output),
+                );
+                futures::future::join3(run, decode, announce).await.0?
+            }
+        };
+        let transcript = Transcript::decode(result.stdout(), &input.token);
+        let result = match &transcript.exit {
+            Some(exit) => result.ended(exit),
+            None => result,
+        };
+        let timeline = program_timeline(&result, &input.token);
+        let compiled = transcript
+            .compiler
+            .as_ref()
+            .is_some_and(Compilation::success);
+        let outcome = result.outcome(if compiled { Phase::Run } else { Phase::Compile });
+        Ok(Evaluation {
+            id: id.to_string(),
+            result,
+            transcript,
+            outcome,
+            args: input.args,
+            env: input.env,
+            timeline,
+        })
     }
 
+    /// Compile `code` to `emit`, which ends up in the stdout of the transcript
     pub async fn compile(
+        &self,
+        client: &str,
         branch: Branch,
         code: String,
         emit: CompileOutput,
-    ) -> Result<(RunResult, String, String)> {
+    ) -> Result<(RunResult, Transcript)> {
+        let limits = self.limits(branch);
+        program::check_code(&code, &limits)?;
         let args = emit.as_opts().iter().map(|x| String::from(*x)).collect();
-        let result = Self::exec(branch, "/usr/local/bin/compile.sh", args, code).await?;
-        let (compiler, output) = Self::parse_output(result.stdout());
-        Ok((result, compiler, output))
+        let input = ScriptInput::new(code);
+        // the assembly or LLVM IR comes where the program's output would
+        let limits = Limits {
+            max_stdout_bytes: limits.max_compile_output_bytes,
+            ..limits
+        };
+        let result = self
+            .exec(
+                runs::new_id(),
+                client,
+                branch,
+                "/usr/local/bin/compile.sh",
+                args,
+                &input,
+                limits,
+                None,
+                None,
+            )
+            .await?;
+        let transcript = Transcript::decode(result.stdout(), &input.token);
+        Ok((result, transcript))
     }
 }
 
 impl Default for Playpen {
     fn default() -> Self {
-        Self::new()
+        Self::new(DockerSandbox::default())
     }
 }
 
@@ -77,6 +422,15 @@ pub enum CompileOutput {
 }
 
 impl CompileOutput {
+    pub const ALL: [CompileOutput; 2] = [CompileOutput::Asm, CompileOutput::Llvm];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CompileOutput::Asm => "asm",
+            CompileOutput::Llvm => "llvm-ir",
+        }
+    }
+
     pub fn as_opts(&self) -> &'static [&'static str] {
         match *self {
             CompileOutput::Asm => &["--pass=asm"],
@@ -98,28 +452,32 @@ impl FromStr for CompileOutput {
 }
 
 /// Highlights compiled asm or llvm ir output according to the given output format
-pub fn highlight(output_format: CompileOutput, output: &str) -> String {
+pub async fn highlight(output_format: CompileOutput, output: &str) -> Result<String> {
     let lexer = match output_format {
         CompileOutput::Asm => "gas",
         CompileOutput::Llvm => "llvm",
     };
 
     let mut child = Command::new("pygmentize")
-        .arg("-l")
-        .arg(lexer)
-        .arg("-f")
-        .arg("html")
+        .args(["-l", lexer, "-f", "html"])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
         .spawn()
-        .unwrap();
-    child
-        .stdin
-        .take()
-        .unwrap()
-        .write_all(output.as_bytes())
-        .unwrap();
-    let output = child.wait_with_output().unwrap();
-    assert!(output.status.success());
-    String::from_utf8(output.stdout).unwrap()
+        .context("failed to start pygmentize")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // fed while its output is read, it does not take all of a large input before writing
+    let feed = async move { stdin.write_all(output.as_bytes()).await };
+    let (fed, highlighted) = tokio::join!(feed, child.wait_with_output());
+    let highlighted = highlighted.context("failed to run pygmentize")?;
+    if !highlighted.status.success() {
+        bail!(
+            "pygmentize failed with {}: {}",
+            highlighted.status,
+            String::from_utf8_lossy(&highlighted.stderr).trim()
+        );
+    }
+    fed.context("failed to pass the output to pygmentize")?;
+    String::from_utf8(highlighted.stdout).context("pygmentize wrote invalid UTF-8")
 }
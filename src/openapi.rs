@@ -0,0 +1,32 @@
+//! The OpenAPI document of the versioned API, generated from the types of its routes
+
+use crate::routes;
+use utoipa::OpenApi;
+
+/// The OpenAPI 3 document of `/api/v1`, served at `/api/v1/openapi.json`
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Pony Playground",
+        description = "Compile, run and share Pony code"
+    ),
+    paths(
+        routes::evaluate,
+        routes::evaluate_stream,
+        routes::evaluate_batch,
+        routes::cancel,
+        routes::compile,
+        routes::create_gist,
+        routes::submit_job,
+        routes::job,
+        routes::stats,
+    ),
+    tags(
+        (name = "evaluate", description = "compile and run programs"),
+        (name = "compile", description = "compile to assembly or LLVM IR"),
+        (name = "jobs", description = "evaluate and compile in the background, polling for the result"),
+        (name = "gists", description = "share programs as GitHub gists"),
+        (name = "stats", description = "how busy the sandbox is"),
+    )
+)]
+pub struct ApiDoc;
@@ -0,0 +1,266 @@
+//! Containers created ahead of time, so requests only need to start them
+//!
+//! The pool learns which containers to keep around from the requests it
+//! serves: the first request for a container spec (image, command and
+//! settings, which mostly boils down to the branch) is a miss and starts
+//! filling a slot for it. A slot that goes unused for `idle_timeout` is
+//! emptied again, and when the image behind a slot changes, its containers
+//! are replaced by ones created from the new image.
+
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::docker::{Container, ContainerSpec};
+use crate::engine::Engine;
+use crate::sandbox::PoolStats;
+
+/// Settings of the container pool
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// how many containers to keep ready per container spec, 0 disables the pool
+    pub size: usize,
+    /// empty a slot after it was not used for this long
+    #[serde(rename = "idle_timeout_secs", with = "crate::config::secs")]
+    pub idle_timeout: Duration,
+    /// how often to look for idle slots and changed images
+    #[serde(rename = "check_interval_secs", with = "crate::config::secs")]
+    pub check_interval: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 2,
+            idle_timeout: Duration::from_secs(15 * 60),
+            check_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What pooled containers are labelled with instead of a request id
+const POOLED: &str = "pooled";
+
+#[derive(Clone)]
+pub(crate) struct Pool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    engine: Engine,
+    instance: String,
+    config: PoolConfig,
+    slots: Mutex<HashMap<ContainerSpec, Slot>>,
+}
+
+struct Slot {
+    ready: VecDeque<Container>,
+    last_used: Instant,
+    /// whether a task is currently creating containers for this slot
+    filling: bool,
+    /// bumped whenever the slot is emptied, so containers still being created get discarded
+    generation: u64,
+    image_id: Option<String>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Slot {
+    fn new() -> Slot {
+        Slot {
+            ready: VecDeque::new(),
+            last_used: Instant::now(),
+            filling: false,
+            generation: 0,
+            image_id: None,
+            hits: 0,
+            misses: 0,
+        }
+    }
+}
+
+impl Pool {
+    pub(crate) fn new(engine: Engine, instance: String, config: PoolConfig) -> Pool {
+        let pool = Pool {
+            inner: Arc::new(PoolInner {
+                engine,
+                instance,
+                config,
+                slots: Mutex::new(HashMap::new()),
+            }),
+        };
+        tokio::spawn(maintain(Arc::downgrade(&pool.inner)));
+        pool
+    }
+
+    /// Hand out a ready container for `spec`, creating one for `request` if there is none
+    pub(crate) async fn checkout(&self, spec: &ContainerSpec, request: &str) -> Result<Container> {
+        let pooled = {
+            let mut slots = self.inner.slots.lock().unwrap();
+            let slot = slots.entry(spec.clone()).or_insert_with(Slot::new);
+            slot.last_used = Instant::now();
+            let pooled = slot.ready.pop_front();
+            if pooled.is_some() {
+                slot.hits += 1;
+            } else {
+                slot.misses += 1;
+            }
+            match &pooled {
+                // its label says `pooled`, so this is what ties it to the request
+                Some(container) => info!(
+                    "pool {} {}: hit, {} runs in {}, {} left",
+                    spec.image,
+                    spec.cmd,
+                    request,
+                    container.id(),
+                    slot.ready.len()
+                ),
+                None => debug!("pool {} {}: miss for {}", spec.image, spec.cmd, request),
+            }
+            pooled
+        };
+        self.fill(spec);
+        match pooled {
+            Some(container) => Ok(container),
+            None => Container::new(&self.inner.engine, spec, &self.inner.instance, request).await,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Vec<PoolStats> {
+        let slots = self.inner.slots.lock().unwrap();
+        let mut stats: Vec<_> = slots
+            .iter()
+            .map(|(spec, slot)| PoolStats {
+                image: spec.image.clone(),
+                cmd: spec.cmd.clone(),
+                ready: slot.ready.len(),
+                hits: slot.hits,
+                misses: slot.misses,
+            })
+            .collect();
+        stats.sort_by(|a, b| (&a.image, &a.cmd).cmp(&(&b.image, &b.cmd)));
+        stats
+    }
+
+    /// Top up the slot for `spec` in the background
+    fn fill(&self, spec: &ContainerSpec) {
+        {
+            let mut slots = self.inner.slots.lock().unwrap();
+            match slots.get_mut(spec) {
+                Some(slot) if !slot.filling => slot.filling = true,
+                _ => return,
+            }
+        }
+        let inner = self.inner.clone();
+        let spec = spec.clone();
+        tokio::spawn(async move {
+            loop {
+                let generation = {
+                    let mut slots = inner.slots.lock().unwrap();
+                    let Some(slot) = slots.get_mut(&spec) else {
+                        return;
+                    };
+                    if slot.ready.len() >= inner.config.size {
+                        slot.filling = false;
+                        return;
+                    }
+                    slot.generation
+                };
+                let created = Container::new(&inner.engine, &spec, &inner.instance, POOLED).await;
+                let mut slots = inner.slots.lock().unwrap();
+                let Some(slot) = slots.get_mut(&spec) else {
+                    return;
+                };
+                match created {
+                    Ok(container) if slot.generation == generation => {
+                        slot.ready.push_back(container)
+                    }
+                    // the slot was emptied in the meantime, this one is dropped and removed
+                    Ok(_) => (),
+                    Err(e) => {
+                        warn!("failed to fill pool for {}: {}", spec.image, e);
+                        slot.filling = false;
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Periodically empty idle slots and rebuild the ones whose image changed
+async fn maintain(inner: Weak<PoolInner>) {
+    let mut interval = {
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        tokio::time::interval(inner.config.check_interval)
+    };
+    loop {
+        interval.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let specs: Vec<ContainerSpec> = {
+            let mut slots = inner.slots.lock().unwrap();
+            slots.retain(|spec, slot| {
+                let idle = slot.last_used.elapsed() >= inner.config.idle_timeout;
+                if idle {
+                    info!(
+                        "pool {} {}: idle, removing {} containers ({} hits, {} misses)",
+                        spec.image,
+                        spec.cmd,
+                        slot.ready.len(),
+                        slot.hits,
+                        slot.misses
+                    );
+                }
+                !idle
+            });
+            slots.keys().cloned().collect()
+        };
+        for spec in specs {
+            let image_id = match spec.engine(&inner.engine).image_id(&spec.image).await {
+                Ok(id) => id,
+                Err(e) => {
+                    warn!("failed to look up image {}: {}", spec.image, e);
+                    continue;
+                }
+            };
+            let mut slots = inner.slots.lock().unwrap();
+            let Some(slot) = slots.get_mut(&spec) else {
+                continue;
+            };
+            match &slot.image_id {
+                Some(known) if *known != image_id => {
+                    info!(
+                        "pool {} {}: image changed to {}, replacing {} containers",
+                        spec.image,
+                        spec.cmd,
+                        image_id,
+                        slot.ready.len()
+                    );
+                    slot.ready.clear();
+                    slot.generation += 1;
+                }
+                _ => debug!(
+                    "pool {} {}: {} ready, {} hits, {} misses",
+                    spec.image,
+                    spec.cmd,
+                    slot.ready.len(),
+                    slot.hits,
+                    slot.misses
+                ),
+            }
+            slot.image_id = Some(image_id);
+            drop(slots);
+            Pool {
+                inner: inner.clone(),
+            }
+            .fill(&spec);
+        }
+    }
+}
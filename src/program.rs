@@ -0,0 +1,129 @@
+//! What a program to evaluate is made of, besides its code
+
+use crate::config::Limits;
+
+/// Arguments and environment variables a program may be given, each
+pub const MAX_ARGS: usize = 32;
+/// Length of a single argument or environment variable in bytes
+pub const MAX_ARG_BYTES: usize = 1024;
+
+/// What the names of the environment variables a program is given start with
+///
+/// Only these are let through, as there are far too many others that change
+/// how the scripts, the loader or libc behave to list them all.
+pub const ENV_PREFIX: &str = "PLAYGROUND_";
+
+/// A program to evaluate, along with what it gets to see when it runs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    pub code: String,
+    /// fed to the program on stdin, not to the compiler
+    pub stdin: Vec<u8>,
+    /// passed to the program, not to the compiler
+    pub args: Vec<String>,
+    /// set for the program on top of the sandbox's environment, not for the
+    /// compiler, each name starting with [`ENV_PREFIX`]
+    pub env: Vec<(String, String)>,
+}
+
+impl From<String> for Program {
+    fn from(code: String) -> Self {
+        Program {
+            code,
+            ..Program::default()
+        }
+    }
+}
+
+/// A program that cannot be run as asked
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum InvalidProgram {
+    #[error("the code is {size} bytes long, at most {limit} are accepted")]
+    CodeTooLong { size: usize, limit: usize },
+    #[error("the code contains NUL bytes")]
+    NulInCode,
+    #[error("stdin is {size} bytes long, at most {limit} are accepted")]
+    StdinTooLarge { size: usize, limit: usize },
+    #[error("at most {limit} {what} are accepted")]
+    TooMany { what: &'static str, limit: usize },
+    #[error("{what} is longer than {limit} bytes")]
+    TooLong { what: String, limit: usize },
+    #[error("{what} contains control characters")]
+    ControlCharacters { what: String },
+    #[error("`{0}` is not a valid environment variable name, use letters, digits and underscores")]
+    InvalidEnvName(String),
+    #[error("environment variable `{0}` does not start with `{ENV_PREFIX}`")]
+    UnprefixedEnvName(String),
+}
+
+impl Program {
+    /// Check the program against the limits of the branch it is to run on
+    pub fn validate(&self, limits: &Limits) -> Result<(), InvalidProgram> {
+        check_code(&self.code, limits)?;
+        if self.stdin.len() > limits.max_stdin_bytes {
+            return Err(InvalidProgram::StdinTooLarge {
+                size: self.stdin.len(),
+                limit: limits.max_stdin_bytes,
+            });
+        }
+        if self.args.len() > MAX_ARGS {
+            return Err(InvalidProgram::TooMany {
+                what: "arguments",
+                limit: MAX_ARGS,
+            });
+        }
+        if self.env.len() > MAX_ARGS {
+            return Err(InvalidProgram::TooMany {
+                what: "environment variables",
+                limit: MAX_ARGS,
+            });
+        }
+        for (i, arg) in self.args.iter().enumerate() {
+            check_value(&format!("argument {}", i + 1), arg)?;
+        }
+        for (name, value) in &self.env {
+            let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return Err(InvalidProgram::InvalidEnvName(name.clone()));
+            }
+            if name.len() <= ENV_PREFIX.len() || !name.starts_with(ENV_PREFIX) {
+                return Err(InvalidProgram::UnprefixedEnvName(name.clone()));
+            }
+            check_value(&format!("environment variable `{}`", name), value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check code to compile, or to share, against the limits of its branch
+pub(crate) fn check_code(code: &str, limits: &Limits) -> Result<(), InvalidProgram> {
+    if code.len() > limits.max_code_bytes {
+        return Err(InvalidProgram::CodeTooLong {
+            size: code.len(),
+            limit: limits.max_code_bytes,
+        });
+    }
+    // no compiler wants them, and they end the file for some tools
+    if code.contains('\0') {
+        return Err(InvalidProgram::NulInCode);
+    }
+    Ok(())
+}
+
+/// Arguments and values travel in lines of the scripts' input, so no line breaks
+/// or any other control characters
+fn check_value(what: &str, value: &str) -> Result<(), InvalidProgram> {
+    if value.len() > MAX_ARG_BYTES {
+        return Err(InvalidProgram::TooLong {
+            what: what.to_string(),
+            limit: MAX_ARG_BYTES,
+        });
+    }
+    if value.chars().any(char::is_control) {
+        return Err(InvalidProgram::ControlCharacters {
+            what: what.to_string(),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,489 @@
+//! Framing between the playground and the scripts running in the sandbox
+//!
+//! The scripts get a header of `key value` lines on stdin, ended by an empty
+//! line, followed by the code to compile. The header carries a random token
+//! the program never sees, the program's own stdin, base64 encoded, and its
+//! arguments and environment variables, one per line:
+//!
+//! ```text
+//! token <token>
+//! stdin aGVsbG8K
+//! arg --verbose
+//! env GREETING=hello
+//!
+//! actor Main
+//! ```
+//!
+//! The token marks the lines the scripts write on stdout:
+//!
+//! ```text
+//! <token> compiler status=0 elapsed_ms=1230 stdout=25 stderr=0 cpu_user_us=1100000 ...
+//! <25 bytes ponyc wrote to stdout><0 bytes ponyc wrote to stderr>
+//! <whatever the program writes to stdout>
+//! <token> exit status=0 elapsed_ms=20 cpu_user_us=3000 cpu_system_us=1000 peak_memory_bytes=...
+//! ```
+//!
+//! A program terminated by a signal exits with `status` 128 plus the signal,
+//! and `signal=<n>` follows it, so it is not mistaken for one that exited
+//! with that status.
+//!
+//! The compiler's output is length-prefixed, so nothing it prints can be
+//! mistaken for the program's. The program's output is passed on as it comes
+//! and ended by the `exit` line once it exits; it is missing if the program
+//! never ran or was killed. The program's stderr is the script's stderr.
+//! The compiler's resource usage is read from the sandbox's cgroup and left
+//! out where it cannot be, as under gVisor; the program's comes from
+//! `wait4()` once it exits.
+//! See `docker/bin/protocol.sh` for the other side.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// What the scripts read on stdin
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptInput {
+    /// marks the frames of the scripts' output
+    pub token: String,
+    pub code: String,
+    /// what the program reads from stdin
+    pub stdin: Vec<u8>,
+    /// arguments of the program, without line breaks
+    pub args: Vec<String>,
+    /// environment variables of the program, without line breaks
+    pub env: Vec<(String, String)>,
+}
+
+impl ScriptInput {
+    /// Input for running `code`, with a fresh token
+    pub fn new(code: String) -> ScriptInput {
+        ScriptInput {
+            token: uuid::Uuid::new_v4().simple().to_string(),
+            code,
+            stdin: vec![],
+            args: vec![],
+            env: vec![],
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut header = format!("token {}\n", self.token);
+        if !self.stdin.is_empty() {
+            header += &format!("stdin {}\n", BASE64.encode(&self.stdin));
+        }
+        for arg in &self.args {
+            header += &format!("arg {}\n", arg);
+        }
+        for (name, value) in &self.env {
+            header += &format!("env {}={}\n", name, value);
+        }
+        let mut input = (header + "\n").into_bytes();
+        input.extend_from_slice(self.code.as_bytes());
+        input
+    }
+
+    /// Parse input the way the scripts do, none if it has no token
+    pub fn decode(input: &[u8]) -> Option<ScriptInput> {
+        let input = std::str::from_utf8(input).ok()?;
+        let (header, code) = input.split_once("\n\n")?;
+        let mut token = None;
+        let mut stdin = vec![];
+        let mut args = vec![];
+        let mut env = vec![];
+        for line in header.lines() {
+            match line.split_once(' ') {
+                Some(("token", value)) => token = Some(value.to_string()),
+                Some(("stdin", value)) => stdin = BASE64.decode(value).ok()?,
+                Some(("arg", value)) => args.push(value.to_string()),
+                Some(("env", value)) => {
+                    let (name, value) = value.split_once('=')?;
+                    env.push((name.to_string(), value.to_string()));
+                }
+                _ => {}
+            }
+        }
+        Some(ScriptInput {
+            token: token?,
+            code: code.to_string(),
+            stdin,
+            args,
+            env,
+        })
+    }
+}
+
+/// What the scripts reported on stdout
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Transcript {
+    /// none if the scripts were stopped before the compiler finished
+    pub compiler: Option<Compilation>,
+    /// what the program wrote to stdout, or the code emitted by `compile.sh`
+    pub stdout: Vec<u8>,
+    /// none if the program never ran or did not get to exit
+    pub exit: Option<Exit>,
+}
+
+/// A run of ponyc
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Compilation {
+    pub status: i32,
+    pub elapsed: Duration,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub usage: Usage,
+}
+
+impl Compilation {
+    pub fn success(&self) -> bool {
+        self.status == 0
+    }
+
+    /// Everything ponyc wrote, stdout first
+    pub fn output(&self) -> String {
+        let mut output = self.stdout.clone();
+        output.extend_from_slice(&self.stderr);
+        String::from_utf8_lossy(&output).into_owned()
+    }
+}
+
+/// How the program exited, as seen by the script running it
+///
+/// A program killed by a signal has an exit status of 128 plus the signal,
+/// and the signal set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Exit {
+    pub status: i32,
+    pub signal: Option<i32>,
+    pub elapsed: Duration,
+    pub usage: Usage,
+}
+
+/// Resources a phase used, none where the sandbox could not tell
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Usage {
+    pub cpu_user_us: Option<u64>,
+    pub cpu_system_us: Option<u64>,
+    /// the most memory the sandbox used while compiling, or the program's
+    /// largest resident set size while running
+    pub peak_memory_bytes: Option<u64>,
+}
+
+impl Usage {
+    fn write(&self, raw: &mut Vec<u8>) {
+        let fields = [
+            ("cpu_user_us", self.cpu_user_us),
+            ("cpu_system_us", self.cpu_system_us),
+            ("peak_memory_bytes", self.peak_memory_bytes),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                write!(raw, " {}={}", key, value).unwrap();
+            }
+        }
+    }
+
+    fn read(fields: &HashMap<String, String>) -> Usage {
+        Usage {
+            cpu_user_us: field(fields, "cpu_user_us"),
+            cpu_system_us: field(fields, "cpu_system_us"),
+            peak_memory_bytes: field(fields, "peak_memory_bytes"),
+        }
+    }
+}
+
+/// Wall-clock time of the phases of a run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Timings {
+    pub compile_ms: Option<u64>,
+    pub run_ms: Option<u64>,
+    /// starting the sandbox and the scripts, everything but compiling and running
+    pub overhead_ms: u64,
+    /// the whole run of the sandbox
+    pub total_ms: u64,
+}
+
+impl Transcript {
+    /// Frame the transcript the way the scripts do
+    pub fn encode(&self, token: &str) -> Vec<u8> {
+        let mut raw = vec![];
+        if let Some(compiler) = &self.compiler {
+            write!(
+                raw,
+                "{} compiler status={} elapsed_ms={} stdout={} stderr={}",
+                token,
+                compiler.status,
+                compiler.elapsed.as_millis(),
+                compiler.stdout.len(),
+                compiler.stderr.len()
+            )
+            .unwrap();
+            compiler.usage.write(&mut raw);
+            raw.push(b'\n');
+            raw.extend_from_slice(&compiler.stdout);
+            raw.extend_from_slice(&compiler.stderr);
+        }
+        raw.extend_from_slice(&self.stdout);
+        if let Some(exit) = &self.exit {
+            write!(raw, "{} exit status={}", token, exit.status).unwrap();
+            if let Some(signal) = exit.signal {
+                write!(raw, " signal={}", signal).unwrap();
+            }
+            write!(raw, " elapsed_ms={}", exit.elapsed.as_millis()).unwrap();
+            exit.usage.write(&mut raw);
+            raw.push(b'\n');
+        }
+        raw
+    }
+
+    /// Take apart what the scripts wrote on stdout
+    ///
+    /// Output cut short by the output limit or a killed container is taken
+    /// as far as it goes. Output without frames, as from scripts not knowing
+    /// the protocol, is passed on as the program's.
+    pub fn decode(raw: &[u8], token: &str) -> Transcript {
+        let mut decoder = Decoder::new(token);
+        decoder.feed(raw);
+        decoder.finish().0
+    }
+
+    /// How long the phases took of a sandbox that ran for `total`
+    pub fn timings(&self, total: Duration) -> Timings {
+        let compile = self.compiler.as_ref().map(|compiler| compiler.elapsed);
+        let run = self.exit.as_ref().map(|exit| exit.elapsed);
+        let phases = compile.unwrap_or_default() + run.unwrap_or_default();
+        Timings {
+            compile_ms: compile.map(millis),
+            run_ms: run.map(millis),
+            overhead_ms: millis(total.saturating_sub(phases)),
+            total_ms: millis(total),
+        }
+    }
+}
+
+/// Something that happened to a run, in the order it did
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// the run waits for a free slot, with this many runs starting before it
+    Queued(usize),
+    /// the compiler finished, or was stopped while writing its output
+    Compiled(Compilation),
+    /// output of the program
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Exited(Exit),
+}
+
+/// Takes apart the scripts' stdout as it arrives
+pub struct Decoder {
+    token: String,
+    state: DecoderState,
+    /// what arrived but could not be handled yet
+    pending: Vec<u8>,
+    transcript: Transcript,
+}
+
+enum DecoderState {
+    /// waiting for the compiler's frame
+    Start,
+    /// reading the compiler's output, this many bytes of stdout and stderr left
+    Compiler { stdout: usize, stderr: usize },
+    /// passing on the program's output until the exit line
+    Program,
+    /// past the exit line
+    Exited,
+    /// no frames, everything is the program's output
+    Unframed,
+}
+
+impl Decoder {
+    pub fn new(token: &str) -> Decoder {
+        Decoder {
+            token: token.to_string(),
+            state: DecoderState::Start,
+            pending: vec![],
+            transcript: Transcript::default(),
+        }
+    }
+
+    /// Take in the next piece of stdout, returning what can be told from it
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Event> {
+        self.pending.extend_from_slice(bytes);
+        let mut events = vec![];
+        while let Some(event) = self.step() {
+            events.extend(event);
+        }
+        events
+    }
+
+    /// Like [`Decoder::feed`], only counting the bytes of the program's output
+    /// instead of keeping them
+    ///
+    /// Returns their count, and where in `bytes` the exit line starts if it
+    /// ended the program's output.
+    pub(crate) fn measure(&mut self, bytes: &[u8]) -> (usize, Option<usize>) {
+        let mut written = 0;
+        let mut exited = None;
+        for event in self.feed(bytes) {
+            match event {
+                Event::Stdout(bytes) => written += bytes.len(),
+                Event::Exited(_) => {
+                    // or it started with what was held back before
+                    let marker = format!("{} exit ", self.token);
+                    exited = Some(find(bytes, marker.as_bytes()).unwrap_or(0));
+                }
+                _ => {}
+            }
+        }
+        self.transcript.stdout.clear();
+        (written, exited)
+    }
+
+    /// Handle what is pending as far as possible, none once more input is needed
+    fn step(&mut self) -> Option<Option<Event>> {
+        match self.state {
+            DecoderState::Start => {
+                let prefix = format!("{} compiler ", self.token);
+                let len = prefix.len().min(self.pending.len());
+                if self.pending[..len] != prefix.as_bytes()[..len] {
+                    self.state = DecoderState::Unframed;
+                    return Some(None);
+                }
+                let (fields, rest) = frame(&self.pending, &self.token, "compiler")?;
+                let stdout = field(&fields, "stdout").unwrap_or(0);
+                let stderr = field(&fields, "stderr").unwrap_or(0);
+                self.transcript.compiler = Some(Compilation {
+                    status: field(&fields, "status").unwrap_or(-1),
+                    elapsed: elapsed(&fields),
+                    stdout: vec![],
+                    stderr: vec![],
+                    usage: Usage::read(&fields),
+                });
+                self.pending = rest.to_vec();
+                self.state = DecoderState::Compiler { stdout, stderr };
+                Some(None)
+            }
+            DecoderState::Compiler { stdout, stderr } => {
+                let compiler = self.transcript.compiler.as_mut()?;
+                let out = stdout.min(self.pending.len());
+                compiler.stdout.extend(self.pending.drain(..out));
+                let err = stderr.min(self.pending.len());
+                compiler.stderr.extend(self.pending.drain(..err));
+                if stdout > out || stderr > err {
+                    self.state = DecoderState::Compiler {
+                        stdout: stdout - out,
+                        stderr: stderr - err,
+                    };
+                    return None;
+                }
+                self.state = DecoderState::Program;
+                Some(Some(Event::Compiled(compiler.clone())))
+            }
+            DecoderState::Program => {
+                // the program may print anything, but it does not know the token
+                let marker = format!("{} exit ", self.token);
+                match find(&self.pending, marker.as_bytes()) {
+                    Some(0) => {
+                        let (fields, rest) = frame(&self.pending, &self.token, "exit")?;
+                        let exit = Exit {
+                            status: field(&fields, "status").unwrap_or(-1),
+                            signal: field(&fields, "signal"),
+                            elapsed: elapsed(&fields),
+                            usage: Usage::read(&fields),
+                        };
+                        self.transcript.exit = Some(exit.clone());
+                        self.pending = rest.to_vec();
+                        self.state = DecoderState::Exited;
+                        Some(Some(Event::Exited(exit)))
+                    }
+                    Some(at) => Some(self.stdout(at)),
+                    // hold back what might be the start of the exit line
+                    None => {
+                        let keep = (1..marker.len())
+                            .rev()
+                            .find(|len| self.pending.ends_with(&marker.as_bytes()[..*len]))
+                            .unwrap_or(0);
+                        let at = self.pending.len() - keep;
+                        (at > 0).then(|| self.stdout(at))
+                    }
+                }
+            }
+            DecoderState::Exited => {
+                self.pending.clear();
+                None
+            }
+            DecoderState::Unframed => {
+                let at = self.pending.len();
+                (at > 0).then(|| self.stdout(at))
+            }
+        }
+    }
+
+    /// Pass on the first `len` pending bytes as the program's output
+    fn stdout(&mut self, len: usize) -> Option<Event> {
+        let bytes: Vec<u8> = self.pending.drain(..len).collect();
+        self.transcript.stdout.extend_from_slice(&bytes);
+        Some(Event::Stdout(bytes))
+    }
+
+    /// Stdout ended, take what is left as far as it goes
+    pub fn finish(mut self) -> (Transcript, Vec<Event>) {
+        let mut events = vec![];
+        match self.state {
+            // cut off in the middle of the compiler's frame line
+            DecoderState::Start => {}
+            DecoderState::Compiler { .. } => {
+                events.extend(self.transcript.compiler.clone().map(Event::Compiled));
+            }
+            DecoderState::Program | DecoderState::Unframed => {
+                let at = self.pending.len();
+                if at > 0 {
+                    events.extend(self.stdout(at));
+                }
+            }
+            DecoderState::Exited => {}
+        }
+        (self.transcript, events)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+/// The fields of a `<token> <kind> key=value...` line at the start of `raw`,
+/// and what follows the line
+fn frame<'a>(
+    raw: &'a [u8],
+    token: &str,
+    kind: &str,
+) -> Option<(HashMap<String, String>, &'a [u8])> {
+    let end = raw.iter().position(|b| *b == b'\n')?;
+    let line = std::str::from_utf8(&raw[..end]).ok()?;
+    let mut words = line.split(' ');
+    if words.next()? != token || words.next()? != kind {
+        return None;
+    }
+    let fields = words
+        .filter_map(|word| word.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Some((fields, &raw[end + 1..]))
+}
+
+fn field<T: std::str::FromStr>(fields: &HashMap<String, String>, key: &str) -> Option<T> {
+    fields.get(key)?.parse().ok()
+}
+
+fn elapsed(fields: &HashMap<String, String>) -> Duration {
+    Duration::from_millis(field(fields, "elapsed_ms").unwrap_or(0))
+}
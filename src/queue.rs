@@ -0,0 +1,278 @@
+//! Bounded execution of sandboxes, with a fair queue for the runs that have to wait
+//!
+//! At most `max_running` sandboxes run at once. Runs beyond that wait in a
+//! queue, which is served round-robin across clients: a client submitting a
+//! dozen programs only gets every other free slot while somebody else is
+//! waiting, instead of pushing everyone else to the back. Once `max_queued`
+//! runs wait, further ones are turned away with [`QueueFull`]. Waiting runs
+//! can be told how many runs start before them whenever that changes.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use utoipa::ToSchema;
+
+/// Settings of the execution queue
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// sandboxes running at the same time
+    pub max_running: usize,
+    /// runs waiting for a free slot before further ones are rejected
+    pub max_queued: usize,
+    /// how long rejected clients are told to wait before trying again
+    #[serde(rename = "retry_after_secs", with = "crate::config::secs")]
+    pub retry_after: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_running: 8,
+            max_queued: 64,
+            retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The queue is full, the run was not accepted
+#[derive(Debug, thiserror::Error)]
+#[error("too many programs waiting to run, retry in {}s", retry_after.as_secs())]
+pub struct QueueFull {
+    pub retry_after: Duration,
+}
+
+/// Occupancy of the execution queue
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct QueueStats {
+    pub running: usize,
+    pub queued: usize,
+    pub max_running: usize,
+    pub max_queued: usize,
+    /// clients with runs waiting
+    pub clients: usize,
+}
+
+pub(crate) struct Executor {
+    config: QueueConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    /// clients with waiting runs, in the order they get their next slot
+    clients: VecDeque<String>,
+    waiting: HashMap<String, VecDeque<Waiter>>,
+}
+
+struct Waiter {
+    id: String,
+    start: oneshot::Sender<()>,
+    /// gets the run's position whenever it changes
+    positions: Option<UnboundedSender<usize>>,
+    /// the position `positions` got last
+    position: Option<usize>,
+}
+
+impl Waiter {
+    fn tell(&mut self, position: usize) {
+        if self.position == Some(position) {
+            return;
+        }
+        self.position = Some(position);
+        if let Some(positions) = &self.positions {
+            let _ = positions.send(position);
+        }
+    }
+}
+
+impl State {
+    fn queued(&self) -> usize {
+        self.waiting.values().map(VecDeque::len).sum()
+    }
+
+    /// Waiting runs in the order they are going to start
+    fn order(&self) -> Vec<&str> {
+        let queues: Vec<_> = self.clients.iter().map(|c| &self.waiting[c]).collect();
+        let rounds = queues.iter().map(|q| q.len()).max().unwrap_or(0);
+        (0..rounds)
+            .flat_map(|round| queues.iter().filter_map(move |q| q.get(round)))
+            .map(|waiter| waiter.id.as_str())
+            .collect()
+    }
+
+    /// Tell the waiting runs whose position changed where they are now
+    fn announce(&mut self) {
+        let rounds = self.waiting.values().map(VecDeque::len).max().unwrap_or(0);
+        let mut position = 0;
+        for round in 0..rounds {
+            for client in &self.clients {
+                if let Some(waiter) = self.waiting.get_mut(client).and_then(|q| q.get_mut(round)) {
+                    waiter.tell(position);
+                    position += 1;
+                }
+            }
+        }
+    }
+
+    /// Remove the waiting run `id` of `client`, returns whether it was still waiting
+    fn remove(&mut self, client: &str, id: &str) -> bool {
+        let Some(queue) = self.waiting.get_mut(client) else {
+            return false;
+        };
+        let Some(index) = queue.iter().position(|w| w.id == id) else {
+            return false;
+        };
+        queue.remove(index);
+        if queue.is_empty() {
+            self.waiting.remove(client);
+            self.clients.retain(|c| c != client);
+        }
+        true
+    }
+}
+
+impl Executor {
+    pub(crate) fn new(config: QueueConfig) -> Arc<Executor> {
+        Arc::new(Executor {
+            config,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Wait for a free slot for run `id` of `client`, `positions` gets the
+    /// number of runs starting before it while it waits
+    ///
+    /// The slot is given back when the returned [`Permit`] is dropped.
+    /// Dropping the future while it waits takes the run out of the queue.
+    pub(crate) async fn acquire(
+        self: &Arc<Self>,
+        client: &str,
+        id: &str,
+        positions: Option<UnboundedSender<usize>>,
+    ) -> Result<Permit, QueueFull> {
+        let start = {
+            let mut state = self.state.lock().unwrap();
+            if state.running < self.config.max_running && state.clients.is_empty() {
+                state.running += 1;
+                return Ok(Permit {
+                    executor: self.clone(),
+                });
+            }
+            if state.queued() >= self.config.max_queued {
+                return Err(QueueFull {
+                    retry_after: self.config.retry_after,
+                });
+            }
+            let (tx, rx) = oneshot::channel();
+            if !state.waiting.contains_key(client) {
+                state.clients.push_back(client.to_string());
+            }
+            state
+                .waiting
+                .entry(client.to_string())
+                .or_default()
+                .push_back(Waiter {
+                    id: id.to_string(),
+                    start: tx,
+                    positions,
+                    position: None,
+                });
+            state.announce();
+            let order = state.order();
+            debug!(
+                "run {} queued at position {} of {}",
+                id,
+                order.iter().position(|w| *w == id).unwrap_or_default(),
+                order.len()
+            );
+            rx
+        };
+        let mut waiting = Wait {
+            executor: self,
+            client,
+            id,
+            start,
+            started: false,
+        };
+        // the sender is only dropped after sending, the executor outlives us
+        let _ = (&mut waiting.start).await;
+        waiting.started = true;
+        Ok(Permit {
+            executor: self.clone(),
+        })
+    }
+
+    pub(crate) fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            running: state.running,
+            queued: state.queued(),
+            max_running: self.config.max_running,
+            max_queued: self.config.max_queued,
+            clients: state.clients.len(),
+        }
+    }
+
+    /// Hand a finished run's slot to the next waiting one, or free it
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(client) = state.clients.pop_front() {
+            let queue = state.waiting.get_mut(&client).expect("queued client");
+            let waiter = queue.pop_front().expect("waiting run");
+            if queue.is_empty() {
+                state.waiting.remove(&client);
+            } else {
+                state.clients.push_back(client);
+            }
+            if waiter.start.send(()).is_ok() {
+                state.announce();
+                return;
+            }
+        }
+        state.running -= 1;
+    }
+}
+
+/// A slot to run a sandbox in, given back on drop
+pub(crate) struct Permit {
+    executor: Arc<Executor>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.executor.release();
+    }
+}
+
+/// A run waiting in the queue
+struct Wait<'a> {
+    executor: &'a Executor,
+    client: &'a str,
+    id: &'a str,
+    start: oneshot::Receiver<()>,
+    started: bool,
+}
+
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        if self.started {
+            return;
+        }
+        let removed = {
+            let mut state = self.executor.state.lock().unwrap();
+            let removed = state.remove(self.client, self.id);
+            state.announce();
+            removed
+        };
+        // `start` is still alive, so if the run is no longer waiting, the slot
+        // was handed to us already and has to be passed on
+        if !removed {
+            self.executor.release();
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Per-client rate limits of the routes that cost us something
+//!
+//! Every client gets a token bucket per route: it holds up to `burst`
+//! requests and refills at `per_minute`. Responses carry the
+//! `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers,
+//! requests finding the bucket empty are answered with 429 and `Retry-After`.
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::client::ClientIp;
+use crate::error::ApiError;
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Rate limits of the individual routes
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub evaluate: RateLimit,
+    pub compile: RateLimit,
+    pub gist: RateLimit,
+    /// submitting jobs, polling for them is not limited
+    pub jobs: RateLimit,
+    pub batch: RateLimit,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            evaluate: RateLimit {
+                burst: 20,
+                per_minute: 30,
+            },
+            compile: RateLimit {
+                burst: 20,
+                per_minute: 30,
+            },
+            // every gist spends the quota of our one GitHub token
+            gist: RateLimit {
+                burst: 5,
+                per_minute: 2,
+            },
+            jobs: RateLimit {
+                burst: 20,
+                per_minute: 30,
+            },
+            // every batch runs up to `max_snippets` programs
+            batch: RateLimit {
+                burst: 2,
+                per_minute: 1,
+            },
+        }
+    }
+}
+
+/// Requests a single client may make to a route
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// requests that can be made at once
+    pub burst: u32,
+    /// requests per minute once the burst is used up, 0 disables the limit
+    pub per_minute: u32,
+}
+
+/// Buckets of all clients of one route
+#[derive(Clone)]
+pub(crate) struct Limiter {
+    limit: RateLimit,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    last_cleanup: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Outcome of taking a token from a bucket
+struct Decision {
+    allowed: bool,
+    remaining: u32,
+    /// until the bucket is full again
+    reset: Duration,
+    /// until the next token is available, if there is none left
+    retry_after: Duration,
+}
+
+/// Forget buckets that refilled completely this often
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+impl Limiter {
+    pub(crate) fn new(limit: RateLimit) -> Limiter {
+        Limiter {
+            limit,
+            buckets: Arc::new(Mutex::new(Buckets {
+                clients: HashMap::new(),
+                last_cleanup: Instant::now(),
+            })),
+        }
+    }
+
+    /// tokens per second
+    fn rate(&self) -> f64 {
+        f64::from(self.limit.per_minute) / 60.0
+    }
+
+    fn check(&self, client: IpAddr) -> Decision {
+        let now = Instant::now();
+        let burst = f64::from(self.limit.burst);
+        let rate = self.rate();
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.last_cleanup) >= CLEANUP_INTERVAL {
+            buckets.last_cleanup = now;
+            buckets.clients.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.clients.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Decision {
+            allowed,
+            remaining: bucket.tokens.floor() as u32,
+            reset: Duration::from_secs_f64((burst - bucket.tokens) / rate),
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens).max(0.0) / rate),
+        }
+    }
+
+    fn headers(&self, decision: &Decision) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit.burst));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(decision.remaining));
+        headers.insert(
+            RATELIMIT_RESET,
+            HeaderValue::from(ceil_secs(decision.reset)),
+        );
+        headers
+    }
+}
+
+/// Seconds to tell clients, rounded up so they do not come back too early
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil() as u64
+}
+
+/// Middleware enforcing the rate limit of a route
+pub(crate) async fn limit(
+    State(limiter): State<Limiter>,
+    ClientIp(client): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    if limiter.limit.per_minute == 0 {
+        return next.run(request).await;
+    }
+    let decision = limiter.check(client);
+    let headers = limiter.headers(&decision);
+    if !decision.allowed {
+        let retry_after = ceil_secs(decision.retry_after);
+        info!(
+            "rate limiting {} on {}, retry in {}s",
+            client,
+            request.uri().path(),
+            retry_after
+        );
+        let mut response = ApiError::RateLimited { retry_after }.into_response();
+        response.headers_mut().extend(headers);
+        return response;
+    }
+    let mut response = next.run(request).await;
+    response.headers_mut().extend(headers);
+    response
+}
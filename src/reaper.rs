@@ -0,0 +1,109 @@
+//! Removal of containers nobody is going to clean up anymore
+//!
+//! Containers are removed when they are dropped, but that never happens if
+//! the playground crashes or is shut down in the middle of a run. Every
+//! container carries labels naming the playground instance that created it,
+//! so the leftovers can be found and removed later on.
+
+use serde::Deserialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+use crate::docker::{INSTANCE_LABEL, LABEL};
+use crate::engine::{Engine, EngineError};
+
+/// Settings of the reaper
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReaperConfig {
+    /// how often to look for containers to remove
+    #[serde(rename = "interval_secs", with = "crate::config::secs")]
+    pub interval: Duration,
+    /// remove containers running for longer than this, or created longer ago
+    /// and never started, unless they wait in the pool
+    #[serde(rename = "max_lifetime_secs", with = "crate::config::secs")]
+    pub max_lifetime: Duration,
+}
+
+impl Default for ReaperConfig {
+    fn default() -> Self {
+        ReaperConfig {
+            interval: Duration::from_secs(60),
+            max_lifetime: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Periodically remove containers that outlived `config.max_lifetime`,
+/// starting right away with the ones earlier instances left behind
+pub(crate) fn spawn(engine: Engine, instance: String, config: ReaperConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+            match reap(&engine, &instance, config.max_lifetime).await {
+                Ok(0) => (),
+                Ok(removed) => info!(
+                    "removed {} containers older than {:?}",
+                    removed, config.max_lifetime
+                ),
+                Err(e) => warn!("failed to reap containers: {}", e),
+            }
+        }
+    })
+}
+
+/// Remove playground containers that have been running for longer than
+/// `max_lifetime`, or that were created longer than `max_lifetime` ago and
+/// never started
+///
+/// Containers of `instance` that were never started wait in the pool and are
+/// left alone, the pool takes care of them. Once the pool hands one out, its
+/// lifetime starts.
+pub(crate) async fn reap(
+    engine: &Engine,
+    instance: &str,
+    max_lifetime: Duration,
+) -> Result<usize, EngineError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let age = |since: i64| Duration::from_secs(now.saturating_sub(since).max(0) as u64);
+    let mut removed = 0;
+    for container in engine.list_containers(LABEL).await? {
+        let ours = container.labels.get(INSTANCE_LABEL).map(String::as_str) == Some(instance);
+        let started = container.state != "created";
+        // started after it was created, so not too old to start with
+        if age(container.created) <= max_lifetime || (ours && !started) {
+            continue;
+        }
+        let age = if started {
+            match engine.inspect(&container.id).await {
+                Ok(details) => age(details.state.started_at.timestamp()),
+                Err(e) if e.is_not_found() => continue,
+                Err(e) => return Err(e),
+            }
+        } else {
+            age(container.created)
+        };
+        if age <= max_lifetime {
+            continue;
+        }
+        debug!(
+            "reaping {} container {} of {:?}, {} {:?} ago",
+            container.state,
+            container.id,
+            container.labels.get(INSTANCE_LABEL),
+            if started { "started" } else { "created" },
+            age
+        );
+        match engine.remove(&container.id).await {
+            Ok(()) => removed += 1,
+            // removed by someone else in the meantime
+            Err(e) if e.is_not_found() => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
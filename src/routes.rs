@@ -1,95 +1,1012 @@
 //! API Routes
 
+use crate::client::ClientIp;
+use crate::error::{ApiError, Problem};
 use crate::github::{self, Client, GIST_DESCRIPTION, GIST_FILENAME, update_gist};
-use crate::{Branch, Playpen, highlight};
+use crate::program::check_code;
+use crate::runs;
+use crate::{
+    ApiDoc, Branch, ByteRange, Color, Compilation, CompileOutput, Encoded, Encoding, Evaluation,
+    Event, Job, JobKind, Limits, Outcome, Phase, Playpen, Program, Renderer, Stats, Stream,
+    Timings, Usage, highlight,
+};
 use anyhow::Result;
 use axum::{
     body::Body,
-    extract::{Json, State},
-    http::{HeaderValue, Response, StatusCode, header::CONTENT_TYPE},
+    extract::{FromRef, FromRequest, Json, Path, Request, State, rejection::JsonRejection},
+    http::{
+        HeaderName, HeaderValue, Response, StatusCode,
+        header::{CONTENT_TYPE, LOCATION},
+    },
+    response::sse::{Event as SseEvent, KeepAlive, Sse},
 };
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::time::Instant;
+use tokio::sync::mpsc;
 use url::Url;
+use utoipa::{OpenApi, ToSchema};
+
+/// A JSON request body, turned away with problem details rather than plain text
+pub(crate) struct Payload<T>(pub T);
+
+impl<S, T> FromRequest<S> for Payload<T>
+where
+    S: Send + Sync,
+    Playpen: FromRef<S>,
+    T: DeserializeOwned,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, ApiError> {
+        match Json::<T>::from_request(request, state).await {
+            Ok(Json(payload)) => Ok(Payload(payload)),
+            Err(rejection) => Err(body_error(rejection, &Playpen::from_ref(state))),
+        }
+    }
+}
+
+/// why a body was rejected, in words the frontend can show
+fn body_error(rejection: JsonRejection, playpen: &Playpen) -> ApiError {
+    if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        ApiError::BodyTooLarge {
+            limit: playpen.config().server.max_body_bytes,
+        }
+    } else {
+        ApiError::InvalidBody {
+            status: rejection.status(),
+            detail: rejection.body_text(),
+        }
+    }
+}
 
 /// evaluate payload
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct Evaluate {
+    /// the program, a `Main` actor and whatever it needs
     code: String,
+    /// the branch of ponyc to compile with, release if none
+    #[schema(value_type = Option<Branch>)]
     branch: Option<String>,
+    /// what the program reads from stdin
+    #[serde(default)]
+    stdin: String,
+    /// the program's arguments
+    #[serde(default)]
+    args: Vec<String>,
+    /// the program's environment variables
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// what to do with ANSI escape codes in the output
+    #[serde(default)]
+    color: Color,
+    /// whether to answer with the compiler's and the program's output apart or together
+    #[serde(default = "enabled")]
+    separate_output: bool,
+    /// how to put the output into strings
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+/// response to an evaluation
+#[derive(Serialize, ToSchema)]
+pub struct EvaluateResponse {
+    success: bool,
+    outcome: Outcome,
+    /// the compiler's output, unless asked for all of the output together
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compiler: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+    /// all of the output, if asked for with `separate_output: false`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    compiler_exit_code: Option<i32>,
+    stdout_truncated: bool,
+    stderr_truncated: bool,
+    /// how much the program wrote to stdout, kept or not
+    stdout_bytes: u64,
+    /// how much the program wrote to stderr, kept or not
+    stderr_bytes: u64,
+    timings: Timings,
+    usage: EvaluateUsage,
+    /// the limits the program ran with
+    limits: Limits,
+    args: Vec<String>,
+    env: BTreeMap<String, String>,
+    encoding: Encoding,
+    /// for each output, whether invalid bytes had to be replaced
+    lossy: BTreeMap<String, bool>,
+    /// for each output, where the replaced bytes were, `utf8_ranges` only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invalid_ranges: Option<BTreeMap<String, Vec<ByteRange>>>,
+    /// the program's stdout and stderr, in the order they were written
+    timeline: Vec<TimelineChunk>,
+}
+
+/// resources used by the phases of an evaluation
+#[derive(Serialize, ToSchema)]
+pub struct EvaluateUsage {
+    compile: Option<Usage>,
+    run: Option<Usage>,
+}
+
+/// a piece of output in the timeline of an evaluation
+#[derive(Serialize, ToSchema)]
+pub struct TimelineChunk {
+    stream: Stream,
+    /// milliseconds since the sandbox started
+    offset_ms: u64,
+    /// the output, encoded as asked for
+    bytes: String,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// how the output of an evaluation is rendered
+#[derive(Clone, Copy)]
+struct Format {
+    color: Color,
+    separate_output: bool,
+    encoding: Encoding,
+}
+
+impl Format {
+    /// encode `output`, rendering escape codes unless it ends up as base64
+    fn render(&self, output: &[u8]) -> Encoded {
+        let mut encoded = self.encoding.encode(output);
+        if self.encoding != Encoding::Base64 {
+            encoded.text = self.color.render(&encoded.text);
+        }
+        encoded
+    }
+}
+
+impl Evaluate {
+    fn format(&self) -> Format {
+        Format {
+            color: self.color,
+            separate_output: self.separate_output,
+            encoding: self.encoding,
+        }
+    }
+
+    fn program(self) -> Program {
+        Program {
+            code: self.code,
+            stdin: self.stdin.into_bytes(),
+            args: self.args,
+            env: self.env.into_iter().collect(),
+        }
+    }
 }
 
 /// evaluate the given code
-pub async fn evaluate(Json(payload): Json<Evaluate>) -> Result<Json<Value>, StatusCode> {
-    let branch = payload
-        .branch
-        .map(|branch| branch.parse().unwrap())
-        .unwrap_or(Branch::Release);
-
-    match Playpen::evaluate(branch, payload.code).await {
-        Ok((status, compiler, program_stdout)) => {
-            let stderr = String::from_utf8_lossy(status.stderr()).into_owned();
-            Ok(Json(json!({
-                "success": status.success(),
-                "compiler": compiler,
-                "stdout": program_stdout,
-                "stderr": stderr
-            })))
+#[utoipa::path(
+    post,
+    path = "/api/v1/evaluate",
+    tag = "evaluate",
+    request_body = Evaluate,
+    responses(
+        (status = 200, description = "the program was compiled and run, successfully or not", body = EvaluateResponse),
+        (status = 400, description = "an invalid value or program", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "the run was cancelled", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "too many requests from the client", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "the sandbox failed", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "the sandbox is unavailable or the queue is full", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn evaluate(
+    State(playpen): State<Playpen>,
+    ClientIp(client): ClientIp,
+    Payload(payload): Payload<Evaluate>,
+) -> Result<Json<EvaluateResponse>, ApiError> {
+    let branch = branch(payload.branch.as_deref())?;
+    let format = payload.format();
+    run_evaluation(
+        &playpen,
+        &client.to_string(),
+        branch,
+        format,
+        payload.program(),
+    )
+    .await
+    .map(Json)
+}
+
+/// evaluate `program`, for a request or a job
+async fn run_evaluation(
+    playpen: &Playpen,
+    client: &str,
+    branch: Branch,
+    format: Format,
+    program: Program,
+) -> Result<EvaluateResponse, ApiError> {
+    match playpen.evaluate(client, branch, program).await {
+        Ok(evaluation) => Ok(evaluate_response(playpen, branch, format, &evaluation)),
+        Err(e) => Err(run_error("Error evaluating playground code", e)),
+    }
+}
+
+/// the branch a request names, release if none
+fn branch(name: Option<&str>) -> Result<Branch, ApiError> {
+    let Some(name) = name else {
+        return Ok(Branch::Release);
+    };
+    name.parse().map_err(|_| ApiError::InvalidValue {
+        field: "branch",
+        value: name.to_string(),
+        valid: Branch::ALL.iter().map(Branch::name).collect(),
+    })
+}
+
+/// the response to an evaluation, also the last event of a streamed one
+fn evaluate_response(
+    playpen: &Playpen,
+    branch: Branch,
+    format: Format,
+    evaluation: &Evaluation,
+) -> EvaluateResponse {
+    let status = &evaluation.result;
+    let compiler = evaluation.transcript.compiler.as_ref();
+    let exit = evaluation.transcript.exit.as_ref();
+    let compiler_output = compiler.map(compiler_output).unwrap_or_default();
+    let stdout = &evaluation.transcript.stdout;
+    let mut response = EvaluateResponse {
+        success: status.success(),
+        outcome: evaluation.outcome.clone(),
+        compiler: None,
+        stdout: None,
+        stderr: None,
+        output: None,
+        compiler_exit_code: compiler.map(|compiler| compiler.status),
+        stdout_truncated: status.stdout_truncated(),
+        stderr_truncated: status.stderr_truncated(),
+        stdout_bytes: evaluation.stdout_bytes(),
+        stderr_bytes: status.stderr_bytes(),
+        timings: evaluation.transcript.timings(status.elapsed()),
+        usage: EvaluateUsage {
+            compile: compiler.map(|compiler| compiler.usage),
+            run: exit.map(|exit| exit.usage),
+        },
+        limits: playpen.limits(branch),
+        args: evaluation.args.clone(),
+        env: evaluation.env.iter().cloned().collect(),
+        encoding: format.encoding,
+        lossy: BTreeMap::new(),
+        invalid_ranges: (format.encoding == Encoding::Utf8Ranges).then(BTreeMap::new),
+        timeline: vec![],
+    };
+    let outputs = if format.separate_output {
+        vec![
+            ("compiler", &mut response.compiler, compiler_output),
+            ("stdout", &mut response.stdout, stdout.clone()),
+            ("stderr", &mut response.stderr, status.stderr().to_vec()),
+        ]
+    } else {
+        vec![(
+            "output",
+            &mut response.output,
+            [&compiler_output, stdout, status.stderr()].concat(),
+        )]
+    };
+    for (name, field, output) in outputs {
+        let encoded = format.render(&output);
+        *field = Some(encoded.text);
+        response.lossy.insert(name.to_string(), encoded.lossy);
+        if let (Some(ranges), Some(invalid)) = (response.invalid_ranges.as_mut(), encoded.invalid) {
+            ranges.insert(name.to_string(), invalid);
         }
-        Err(e) => {
-            error!("Error evaluating playground code: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+    let mut stdout = OutputChunks::new(format);
+    let mut stderr = OutputChunks::new(format);
+    response.timeline = evaluation
+        .timeline
+        .iter()
+        .map(|chunk| {
+            let stream = match chunk.stream {
+                Stream::Stdout => &mut stdout,
+                Stream::Stderr => &mut stderr,
+            };
+            TimelineChunk {
+                stream: chunk.stream,
+                offset_ms: chunk.offset.as_millis() as u64,
+                bytes: stream.push(&chunk.bytes),
+            }
+        })
+        .collect();
+    response
+}
+
+/// everything the compiler wrote, stdout first
+fn compiler_output(compiler: &Compilation) -> Vec<u8> {
+    [compiler.stdout.as_slice(), &compiler.stderr].concat()
+}
+
+/// evaluate the given code, streaming its output as server-sent events
+///
+/// A `compiler` event carries the compiler's output once it is done, `stdout`
+/// and `stderr` events carry the program's output as it arrives. The last
+/// event is `outcome`, with the same payload as `/evaluate.json`, or `error`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/evaluate/stream",
+    tag = "evaluate",
+    request_body = Evaluate,
+    responses(
+        (
+            status = 200,
+            description = "server-sent events: `run` with the `run_id`, `compiler` with the compiler's \
+                `output` and `exit_code`, `stdout` and `stderr` with the program's output in `data`, \
+                and finally `outcome` with the same body as `/api/v1/evaluate` or `error` with a problem",
+            content_type = "text/event-stream",
+        ),
+        (status = 400, description = "an invalid value or program", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "too many requests from the client", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn evaluate_stream(
+    State(playpen): State<Playpen>,
+    ClientIp(client): ClientIp,
+    Payload(payload): Payload<Evaluate>,
+) -> Result<Sse<impl futures::Stream<Item = Result<SseEvent, Infallible>>>, ApiError> {
+    let branch = branch(payload.branch.as_deref())?;
+    let format = payload.format();
+    let program = payload.program();
+    // rather a proper status than an error event
+    program.validate(&playpen.limits(branch))?;
+
+    let id = runs::new_id();
+    let (sse, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (events, mut decoded) = mpsc::unbounded_channel();
+        let forward = async {
+            let mut stdout = OutputChunks::new(format);
+            let mut stderr = OutputChunks::new(format);
+            while let Some(event) = decoded.recv().await {
+                let event = match event {
+                    Event::Queued(position) => SseEvent::default()
+                        .event("queued")
+                        .json_data(json!({"position": position})),
+                    Event::Compiled(compiler) => {
+                        SseEvent::default().event("compiler").json_data(json!({
+                            "output": format.render(&compiler_output(&compiler)).text,
+                            "exit_code": compiler.status,
+                        }))
+                    }
+                    Event::Stdout(bytes) => SseEvent::default()
+                        .event("stdout")
+                        .json_data(json!({"data": stdout.push(&bytes)})),
+                    Event::Stderr(bytes) => SseEvent::default()
+                        .event("stderr")
+                        .json_data(json!({"data": stderr.push(&bytes)})),
+                    // the outcome tells
+                    Event::Exited(_) => continue,
+                };
+                let _ = sse.send(event.unwrap());
+            }
+        };
+        let client = client.to_string();
+        let evaluate = playpen.evaluate_streaming(&id, &client, branch, program, events);
+        // what to cancel the run with, only once it can be
+        let _ = sse.send(
+            SseEvent::default()
+                .event("run")
+                .json_data(json!({ "run_id": id }))
+                .unwrap(),
+        );
+        let evaluation = tokio::select! {
+            (evaluation, ()) = futures::future::join(evaluate, forward) => evaluation,
+            // dropping the evaluation stops it, rather than running it for nobody
+            () = sse.closed() => {
+                debug!("client went away, cancelled run {}", id);
+                return;
+            }
+        };
+        let last = match evaluation {
+            Ok(evaluation) => SseEvent::default()
+                .event("outcome")
+                .json_data(evaluate_response(&playpen, branch, format, &evaluation)),
+            Err(e) => {
+                let error = run_error("Error evaluating playground code", e);
+                SseEvent::default()
+                    .event("error")
+                    .json_data(error.problem())
+            }
+        };
+        let _ = sse.send(last.unwrap());
+    });
+    Ok(Sse::new(futures::stream::poll_fn(move |cx| {
+        rx.poll_recv(cx).map(|event| event.map(Ok))
+    }))
+    .keep_alive(KeepAlive::default()))
+}
+
+/// Renders a stream of output chunks as the request asked for
+///
+/// As text, characters and escape codes split across chunks are kept together,
+/// as base64 every chunk is encoded on its own.
+struct OutputChunks {
+    encoding: Encoding,
+    incomplete: Vec<u8>,
+    renderer: Renderer,
+}
+
+impl OutputChunks {
+    fn new(format: Format) -> Self {
+        OutputChunks {
+            encoding: format.encoding,
+            incomplete: vec![],
+            renderer: Renderer::new(format.color),
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> String {
+        if self.encoding == Encoding::Base64 {
+            return self.encoding.encode(chunk).text;
+        }
+        let mut bytes = std::mem::take(&mut self.incomplete);
+        bytes.extend_from_slice(chunk);
+        // an incomplete character at the end may be completed by the next chunk
+        if let Err(e) = std::str::from_utf8(&bytes)
+            && e.error_len().is_none()
+        {
+            self.incomplete = bytes.split_off(e.valid_up_to());
         }
+        self.renderer.push(&String::from_utf8_lossy(&bytes))
     }
 }
 
 /// compile code payload
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct Compile {
+    /// what to compile to, `asm` or `llvm-ir`
+    #[schema(example = "asm")]
     emit: String,
     code: String,
+    /// the branch of ponyc to compile with, release if none
+    #[schema(value_type = Option<Branch>)]
     branch: Option<String>,
+    /// what to do with ANSI escape codes in the compiler's errors
+    #[serde(default)]
+    color: Color,
+    /// whether to answer with highlighted HTML or plain text
+    #[serde(default = "enabled")]
+    highlight: bool,
+}
+
+/// response to compiling, with either the `result` or the compiler's `error`
+#[derive(Serialize, ToSchema)]
+pub struct CompileResponse {
+    /// the assembly or LLVM IR
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<String>,
+    /// why compiling failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// whether the `result` was cut off at `max_compile_output_bytes`
+    truncated: bool,
+    /// how the sandbox ended, e.g. whether compiling timed out
+    outcome: Outcome,
+    timings: Timings,
+    usage: CompileUsage,
+}
+
+/// resources used by compiling
+#[derive(Serialize, ToSchema)]
+pub struct CompileUsage {
+    compile: Option<Usage>,
 }
 
 /// compile the given code
-pub async fn compile(Json(payload): Json<Compile>) -> Result<Json<Value>, StatusCode> {
-    let emit = payload.emit.parse().unwrap();
-    let branch = payload
-        .branch
-        .map(|branch| branch.parse().unwrap())
-        .unwrap_or(Branch::Release);
-
-    match Playpen::compile(branch, payload.code, emit).await {
-        Ok((result, _compiler_output, program_stdout)) => Ok(Json(if result.success() {
-            let output = highlight(emit, &program_stdout);
-            json!({
-                "result": output,
-            })
-        } else {
-            json!({
-                "error": String::from_utf8_lossy(result.stderr()),
-            })
-        })),
+#[utoipa::path(
+    post,
+    path = "/api/v1/compile",
+    tag = "compile",
+    request_body = Compile,
+    responses(
+        (status = 200, description = "the code was compiled, successfully or not", body = CompileResponse),
+        (status = 400, description = "an invalid value", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "too many requests from the client", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "the output could not be highlighted", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "the sandbox failed", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "the sandbox is unavailable or the queue is full", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn compile(
+    State(playpen): State<Playpen>,
+    ClientIp(client): ClientIp,
+    Payload(payload): Payload<Compile>,
+) -> Result<Json<CompileResponse>, ApiError> {
+    let emit = payload.emit()?;
+    let branch = branch(payload.branch.as_deref())?;
+    run_compile(&playpen, &client.to_string(), branch, emit, payload)
+        .await
+        .map(Json)
+}
+
+impl Compile {
+    fn emit(&self) -> Result<CompileOutput, ApiError> {
+        self.emit.parse().map_err(|_| ApiError::InvalidValue {
+            field: "emit",
+            value: self.emit.clone(),
+            valid: CompileOutput::ALL.iter().map(CompileOutput::name).collect(),
+        })
+    }
+}
+
+/// compile the code of `payload`, for a request or a job
+async fn run_compile(
+    playpen: &Playpen,
+    client: &str,
+    branch: Branch,
+    emit: CompileOutput,
+    payload: Compile,
+) -> Result<CompileResponse, ApiError> {
+    match playpen.compile(client, branch, payload.code, emit).await {
+        Ok((result, transcript)) => {
+            let mut response = CompileResponse {
+                result: None,
+                error: None,
+                truncated: result.stdout_truncated(),
+                outcome: result.outcome(Phase::Compile),
+                timings: transcript.timings(result.elapsed()),
+                usage: CompileUsage {
+                    compile: transcript.compiler.as_ref().map(|compiler| compiler.usage),
+                },
+            };
+            let compiled = transcript
+                .compiler
+                .as_ref()
+                .is_some_and(Compilation::success);
+            // cut off, the sandbox was killed after compiling went fine
+            if result.success() || (compiled && response.truncated) {
+                let output = String::from_utf8_lossy(&transcript.stdout);
+                response.result = Some(if payload.highlight {
+                    highlight(emit, &output).await.map_err(|e| {
+                        let error = ApiError::Highlight(e);
+                        error.log("Error highlighting");
+                        error
+                    })?
+                } else {
+                    output.into_owned()
+                });
+            } else {
+                // what ponyc complained about, and the script if it did not get that far
+                let mut error = transcript
+                    .compiler
+                    .map(|compiler| compiler.stderr)
+                    .unwrap_or_default();
+                error.extend_from_slice(result.stderr());
+                let error = String::from_utf8_lossy(&error);
+                response.error = Some(if error.trim().is_empty() {
+                    stopped(&response.outcome)
+                } else {
+                    payload.color.render(&error)
+                });
+            }
+            Ok(response)
+        }
+        Err(e) => Err(run_error("Error compiling", e)),
+    }
+}
+
+/// why compiling failed when nothing said so
+fn stopped(outcome: &Outcome) -> String {
+    if outcome.timed_out {
+        return "compiling timed out".to_string();
+    }
+    match (outcome.signal_name, outcome.signal, outcome.exit_code) {
+        (Some(name), _, _) => format!("compiling was stopped by {name}"),
+        (None, Some(signal), _) => format!("compiling was stopped by signal {signal}"),
+        (None, None, Some(code)) => format!("compiling failed with exit code {code}"),
+        (None, None, None) => "compiling failed".to_string(),
+    }
+}
+
+/// tell apart why a run failed, logging what went wrong on our side
+fn run_error(context: &str, e: anyhow::Error) -> ApiError {
+    let error = ApiError::from_run(e);
+    error.log(context);
+    error
+}
+
+/// batch evaluate payload
+#[derive(Deserialize, ToSchema)]
+pub struct Batch {
+    /// the branch of ponyc to evaluate all snippets with, release if none
+    #[schema(value_type = Option<Branch>)]
+    branch: Option<String>,
+    snippets: Vec<Snippet>,
+}
+
+/// a program of a batch
+#[derive(Deserialize, ToSchema)]
+pub struct Snippet {
+    /// what to tell the snippet by in the response, e.g. the file it is from
+    name: String,
+    code: String,
+    /// what the program reads from stdin
+    #[serde(default)]
+    stdin: String,
+    /// what the program has to write to stdout to pass, anything if none
+    expected_stdout: Option<String>,
+}
+
+/// how a snippet of a batch did
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SnippetStatus {
+    /// compiled, ran successfully and wrote what was expected, if anything
+    Passed,
+    /// did not compile, or did not run successfully
+    Failed,
+    /// ran successfully, but wrote something else than expected
+    Mismatched,
+    /// could not be evaluated at all
+    Error,
+}
+
+/// response to a batch evaluation
+#[derive(Serialize, ToSchema)]
+pub struct BatchResponse {
+    /// whether every snippet passed
+    success: bool,
+    summary: BatchSummary,
+    /// the limits every snippet ran with
+    limits: Limits,
+    /// in the order of the request
+    snippets: Vec<SnippetResult>,
+}
+
+/// snippets of a batch by status
+#[derive(Default, Serialize, ToSchema)]
+pub struct BatchSummary {
+    total: usize,
+    passed: usize,
+    failed: usize,
+    mismatched: usize,
+    errors: usize,
+    /// wall-clock time of the whole batch
+    elapsed_ms: u64,
+}
+
+/// the outcome of a snippet of a batch
+#[derive(Serialize, ToSchema)]
+pub struct SnippetResult {
+    name: String,
+    status: SnippetStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<Outcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compiler: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<Timings>,
+    /// why the snippet could not be evaluated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Problem>,
+}
+
+/// evaluate many snippets, checking what they write to stdout
+///
+/// The snippets are evaluated a few at a time, with the same limits as any
+/// other evaluation, and are answered with all at once.
+#[utoipa::path(
+    post,
+    path = "/api/v1/batch",
+    tag = "evaluate",
+    request_body = Batch,
+    responses(
+        (status = 200, description = "every snippet was evaluated, or tried to be", body = BatchResponse),
+        (status = 400, description = "an invalid value", body = Problem, content_type = "application/problem+json"),
+        (status = 413, description = "too many snippets", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "too many requests from the client", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn evaluate_batch(
+    State(playpen): State<Playpen>,
+    ClientIp(client): ClientIp,
+    Payload(payload): Payload<Batch>,
+) -> Result<Json<BatchResponse>, ApiError> {
+    let branch = branch(payload.branch.as_deref())?;
+    let limit = playpen.config().batch.max_snippets;
+    if payload.snippets.len() > limit {
+        return Err(ApiError::TooManySnippets {
+            count: payload.snippets.len(),
+            limit,
+        });
+    }
+    let started = Instant::now();
+    let programs = payload
+        .snippets
+        .iter()
+        .map(|snippet| Program {
+            stdin: snippet.stdin.clone().into_bytes(),
+            ..Program::from(snippet.code.clone())
+        })
+        .collect();
+    let evaluations = playpen
+        .evaluate_batch(&client.to_string(), branch, programs)
+        .await;
+
+    let mut summary = BatchSummary {
+        total: payload.snippets.len(),
+        ..BatchSummary::default()
+    };
+    let snippets: Vec<_> = payload
+        .snippets
+        .into_iter()
+        .zip(evaluations)
+        .map(|(snippet, evaluation)| {
+            let result = snippet_result(snippet, evaluation);
+            match result.status {
+                SnippetStatus::Passed => summary.passed += 1,
+                SnippetStatus::Failed => summary.failed += 1,
+                SnippetStatus::Mismatched => summary.mismatched += 1,
+                SnippetStatus::Error => summary.errors += 1,
+            }
+            result
+        })
+        .collect();
+    summary.elapsed_ms = started.elapsed().as_millis() as u64;
+    Ok(Json(BatchResponse {
+        success: summary.passed == summary.total,
+        summary,
+        limits: playpen.limits(branch),
+        snippets,
+    }))
+}
+
+/// how `snippet` did, judging by its `evaluation`
+fn snippet_result(snippet: Snippet, evaluation: Result<Evaluation>) -> SnippetResult {
+    let evaluation = match evaluation {
+        Ok(evaluation) => evaluation,
         Err(e) => {
-            error!("Error compiling: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            let error = run_error("Error evaluating snippet of a batch", e);
+            return SnippetResult {
+                name: snippet.name,
+                status: SnippetStatus::Error,
+                outcome: None,
+                compiler: None,
+                stdout: None,
+                stderr: None,
+                timings: None,
+                error: Some(error.problem()),
+            };
         }
+    };
+    let status = &evaluation.result;
+    let stdout = &evaluation.transcript.stdout;
+    let snippet_status = if !status.success() {
+        SnippetStatus::Failed
+    } else if snippet
+        .expected_stdout
+        .is_some_and(|expected| expected.as_bytes() != stdout.as_slice())
+    {
+        SnippetStatus::Mismatched
+    } else {
+        SnippetStatus::Passed
+    };
+    let compiler = evaluation.transcript.compiler.as_ref();
+    SnippetResult {
+        name: snippet.name,
+        status: snippet_status,
+        outcome: Some(evaluation.outcome.clone()),
+        compiler: Some(
+            String::from_utf8_lossy(&compiler.map(compiler_output).unwrap_or_default())
+                .into_owned(),
+        ),
+        stdout: Some(String::from_utf8_lossy(stdout).into_owned()),
+        stderr: Some(String::from_utf8_lossy(status.stderr()).into_owned()),
+        timings: Some(evaluation.transcript.timings(status.elapsed())),
+        error: None,
     }
 }
 
+/// cancel run payload
+#[derive(Deserialize, ToSchema)]
+pub struct Cancel {
+    /// the id the `run` event announced
+    run_id: String,
+}
+
+/// response to cancelling a run
+#[derive(Serialize, ToSchema)]
+pub struct Cancelled {
+    /// the id of the cancelled run
+    cancelled: String,
+}
+
+/// cancel a run, waiting or running, by the id `/evaluate.sse` announced it with
+#[utoipa::path(
+    post,
+    path = "/api/v1/cancel",
+    tag = "evaluate",
+    request_body = Cancel,
+    responses(
+        (status = 200, description = "the run was cancelled", body = Cancelled),
+        (status = 404, description = "no such run of the client in flight", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn cancel(
+    State(playpen): State<Playpen>,
+    ClientIp(client): ClientIp,
+    Payload(payload): Payload<Cancel>,
+) -> Result<Json<Cancelled>, ApiError> {
+    // runs of other clients are none of the caller's business
+    if playpen.cancel(&payload.run_id, &client.to_string()) {
+        Ok(Json(Cancelled {
+            cancelled: payload.run_id,
+        }))
+    } else {
+        Err(ApiError::NotFound(format!(
+            "no run {} in flight",
+            payload.run_id
+        )))
+    }
+}
+
+/// runtime statistics of the sandbox
+#[utoipa::path(
+    get,
+    path = "/api/v1/stats",
+    tag = "stats",
+    responses((status = 200, description = "the pools and the queue", body = Stats))
+)]
+pub async fn stats(State(playpen): State<Playpen>) -> Json<Stats> {
+    Json(playpen.stats())
+}
+
+/// submit job payload, an evaluate or compile payload along with its `kind`
+#[derive(Deserialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SubmitJob {
+    Evaluate(Evaluate),
+    Compile(Compile),
+}
+
+/// run an evaluation or compilation in the background
+///
+/// Invalid requests are turned away right away, everything that can only go
+/// wrong while running ends the job with the problem it ran into.
+#[utoipa::path(
+    post,
+    path = "/api/v1/jobs",
+    tag = "jobs",
+    request_body = SubmitJob,
+    responses(
+        (
+            status = 202,
+            description = "the job was submitted",
+            body = Job,
+            headers(("Location" = String, description = "where to poll for the job")),
+        ),
+        (status = 400, description = "an invalid value or program", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "too many requests from the client", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn submit_job(
+    State(playpen): State<Playpen>,
+    ClientIp(client): ClientIp,
+    Payload(payload): Payload<SubmitJob>,
+) -> Result<(StatusCode, [(HeaderName, String); 1], Json<Job>), ApiError> {
+    let client = client.to_string();
+    let job = match payload {
+        SubmitJob::Evaluate(payload) => {
+            let branch = branch(payload.branch.as_deref())?;
+            let format = payload.format();
+            let program = payload.program();
+            program.validate(&playpen.limits(branch))?;
+            let job = {
+                let playpen = playpen.clone();
+                async move {
+                    job_result(run_evaluation(&playpen, &client, branch, format, program).await)
+                }
+            };
+            playpen.jobs().submit(JobKind::Evaluate, job)
+        }
+        SubmitJob::Compile(payload) => {
+            let emit = payload.emit()?;
+            let branch = branch(payload.branch.as_deref())?;
+            check_code(&payload.code, &playpen.limits(branch))?;
+            let job = {
+                let playpen = playpen.clone();
+                async move { job_result(run_compile(&playpen, &client, branch, emit, payload).await) }
+            };
+            playpen.jobs().submit(JobKind::Compile, job)
+        }
+    };
+    let location = format!("/api/v1/jobs/{}", job.id);
+    Ok((StatusCode::ACCEPTED, [(LOCATION, location)], Json(job)))
+}
+
+/// what a job ends with, the response or the problem
+fn job_result(result: Result<impl Serialize, ApiError>) -> Result<Value, Value> {
+    match result {
+        Ok(response) => Ok(json!(response)),
+        Err(error) => Err(json!(error.problem())),
+    }
+}
+
+/// a job and, once it finished, its result
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{id}",
+    tag = "jobs",
+    params(("id" = String, Path, description = "the id the job was submitted with")),
+    responses(
+        (status = 200, description = "the job, running or finished", body = Job),
+        (status = 404, description = "no such job, or it expired", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn job(
+    State(playpen): State<Playpen>,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, ApiError> {
+    match playpen.jobs().get(&id) {
+        Some(job) => Ok(Json(job)),
+        None => Err(ApiError::NotFound(format!("no job {}, or it expired", id))),
+    }
+}
+
+/// the OpenAPI document of the versioned API
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
 /// create gist payload
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct CreateGist {
     code: String,
-    base_url: Url,
+    /// the playground to link to, the gist is added to its query
+    #[schema(format = "uri")]
+    base_url: String,
+    #[schema(value_type = Branch)]
     branch: String,
 }
 
+/// response to creating a gist
+#[derive(Serialize, ToSchema)]
+pub struct GistResponse {
+    gist_id: String,
+    /// the gist on GitHub
+    gist_url: String,
+    /// the playground with the gist loaded
+    play_url: String,
+}
+
 /// create a gist
+#[utoipa::path(
+    post,
+    path = "/api/v1/gists",
+    tag = "gists",
+    request_body = CreateGist,
+    responses(
+        (status = 200, description = "the gist was created", body = GistResponse),
+        (status = 400, description = "an invalid value", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "too many requests from the client", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "GitHub failed", body = Problem, content_type = "application/problem+json"),
+    )
+)]
 pub async fn create_gist(
     State(client): State<Client>,
-    Json(payload): Json<CreateGist>,
-) -> Result<Json<Value>, StatusCode> {
+    State(playpen): State<Playpen>,
+    Payload(payload): Payload<CreateGist>,
+) -> Result<Json<GistResponse>, ApiError> {
+    let branch = branch(Some(&payload.branch))?;
+    let mut url = base_url(&payload.base_url)?;
+    check_code(&payload.code, &playpen.limits(branch))?;
     match github::create_gist(
         &client,
         GIST_DESCRIPTION.into(),
@@ -99,10 +1016,9 @@ pub async fn create_gist(
     .await
     {
         Ok(gist) => {
-            let mut url = payload.base_url;
             url.query_pairs_mut().append_pair("gist", &gist.id);
-            if payload.branch != "release" {
-                url.query_pairs_mut().append_pair("branch", &payload.branch);
+            if branch != Branch::Release {
+                url.query_pairs_mut().append_pair("branch", branch.name());
             }
             let url: String = url.into();
 
@@ -113,24 +1029,51 @@ pub async fn create_gist(
             )
             .await
             {
-                Ok(gist) => Ok(Json(json!({
-                    "gist_id": gist.id,
-                    "gist_url": gist.html_url,
-                    "play_url": url,
-                }))),
+                Ok(gist) => Ok(Json(GistResponse {
+                    gist_id: gist.id,
+                    gist_url: gist.html_url.into(),
+                    play_url: url,
+                })),
                 Err(e) => {
-                    error!("Error updating gist to append URL to description: {}", e);
-                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+                    let error = ApiError::Github(e);
+                    error.log("Error updating gist to append URL to description");
+                    Err(error)
                 }
             }
         }
         Err(e) => {
-            error!("Error creating gist: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            let error = ApiError::Github(e);
+            error.log("Error creating gist");
+            Err(error)
         }
     }
 }
 
+/// Longest `base_url` a gist may link to
+const MAX_URL_BYTES: usize = 2048;
+
+/// the playground a gist links to, a plain web address
+fn base_url(url: &str) -> Result<Url, ApiError> {
+    let invalid = |reason| ApiError::InvalidUrl {
+        field: "base_url",
+        reason,
+    };
+    if url.len() > MAX_URL_BYTES {
+        return Err(invalid("it is longer than 2048 bytes"));
+    }
+    let url = Url::parse(url).map_err(|_| invalid("it cannot be parsed"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid("only http and https URLs are accepted"));
+    }
+    if url.host().is_none() {
+        return Err(invalid("it has no host"));
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(invalid("it may not contain credentials"));
+    }
+    Ok(url)
+}
+
 // static routes
 
 static APPLICATION_JAVASCRIPT: HeaderValue = HeaderValue::from_static("application/javascript");
@@ -0,0 +1,107 @@
+//! Runs in flight, to cancel them by id
+//!
+//! Cancelling a run drops it wherever it is: a run waiting in the queue gives
+//! up its place, a running one has its container removed, which kills it.
+//! Only the client that started a run may cancel it.
+
+use anyhow::Result;
+use futures::future::{AbortHandle, AbortRegistration, Abortable};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+/// The run was cancelled before it finished
+#[derive(Debug, thiserror::Error)]
+#[error("run {id} was cancelled")]
+pub struct Cancelled {
+    pub id: String,
+}
+
+/// A fresh id for a run
+pub(crate) fn new_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// A run in flight and who started it
+struct Running {
+    client: String,
+    handle: AbortHandle,
+}
+
+#[derive(Default)]
+pub(crate) struct Runs {
+    running: Mutex<HashMap<String, Running>>,
+}
+
+impl Runs {
+    /// Run `future` as run `id` of `client` until it is done or cancelled
+    pub(crate) async fn run<T>(
+        &self,
+        id: &str,
+        client: &str,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        self.register(id, client).run(future).await
+    }
+
+    /// Make run `id` of `client` cancellable right away, before it is run
+    pub(crate) fn register(&self, id: &str, client: &str) -> Registered<'_> {
+        let (handle, registration) = AbortHandle::new_pair();
+        let running = Running {
+            client: client.to_string(),
+            handle,
+        };
+        self.running.lock().unwrap().insert(id.to_string(), running);
+        Registered {
+            forget: Forget {
+                runs: self,
+                id: id.to_string(),
+            },
+            registration,
+        }
+    }
+
+    /// Cancel run `id` on behalf of `client`, returns whether it was still
+    /// running and `client` started it
+    pub(crate) fn cancel(&self, id: &str, client: &str) -> bool {
+        let mut running = self.running.lock().unwrap();
+        if running.get(id).is_none_or(|run| run.client != client) {
+            return false;
+        }
+        running.remove(id).unwrap().handle.abort();
+        true
+    }
+}
+
+/// A registered run
+pub(crate) struct Registered<'a> {
+    forget: Forget<'a>,
+    registration: AbortRegistration,
+}
+
+impl Registered<'_> {
+    /// Run `future` until it is done or cancelled, which it may have been already
+    pub(crate) async fn run<T>(self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        let Registered {
+            forget,
+            registration,
+        } = self;
+        Abortable::new(future, registration)
+            .await
+            .map_err(|_| Cancelled {
+                id: forget.id.clone(),
+            })?
+    }
+}
+
+/// Forgets a run once it is over, however it ended
+struct Forget<'a> {
+    runs: &'a Runs,
+    id: String,
+}
+
+impl Drop for Forget<'_> {
+    fn drop(&mut self) {
+        self.runs.running.lock().unwrap().remove(&self.id);
+    }
+}
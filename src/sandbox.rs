@@ -0,0 +1,392 @@
+//! Sandbox backends that run the in-container scripts
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use utoipa::ToSchema;
+
+use crate::Branch;
+use crate::config::{Limits, Profile, Runtime};
+use crate::protocol::{Exit, ScriptInput, Transcript};
+
+#[derive(Debug)]
+pub(crate) enum ChildResult {
+    ExitCode(i32),
+    Signal(i32),
+    TimedOut,
+}
+
+#[derive(Debug)]
+pub struct RunResult {
+    pub(crate) result: ChildResult,
+    pub(crate) stdout: Vec<u8>,
+    pub(crate) stderr: Vec<u8>,
+    /// bytes written to stdout, including the ones cut off by the output
+    /// limit, only the program's where the sandbox tells them apart
+    pub(crate) stdout_bytes: u64,
+    /// bytes written to stderr, including the ones cut off by the output limit
+    pub(crate) stderr_bytes: u64,
+    pub(crate) stdout_truncated: bool,
+    pub(crate) stderr_truncated: bool,
+    /// from asking for a sandbox until it exited
+    pub(crate) elapsed: Duration,
+    /// the kept output of both streams, in the order it arrived
+    pub(crate) timeline: Vec<Chunk>,
+}
+
+impl RunResult {
+    fn with_result(result: ChildResult, stdout: Vec<u8>, stderr: Vec<u8>) -> Self {
+        let timeline = [(Stream::Stdout, &stdout), (Stream::Stderr, &stderr)]
+            .into_iter()
+            .filter(|(_, bytes)| !bytes.is_empty())
+            .map(|(stream, bytes)| Chunk {
+                stream,
+                offset: Duration::ZERO,
+                bytes: bytes.clone(),
+            })
+            .collect();
+        Self {
+            result,
+            stdout_bytes: stdout.len() as u64,
+            stderr_bytes: stderr.len() as u64,
+            stdout_truncated: false,
+            stderr_truncated: false,
+            stdout,
+            stderr,
+            elapsed: Duration::ZERO,
+            timeline,
+        }
+    }
+
+    /// A run that exited on its own with the given exit code
+    pub fn exited(code: i32, stdout: Vec<u8>, stderr: Vec<u8>) -> Self {
+        Self::with_result(ChildResult::ExitCode(code), stdout, stderr)
+    }
+
+    /// A run that was terminated by the given signal
+    pub fn signaled(signal: i32, stdout: Vec<u8>, stderr: Vec<u8>) -> Self {
+        Self::with_result(ChildResult::Signal(signal), stdout, stderr)
+    }
+
+    /// A run that was killed after exceeding its timeout
+    pub fn timed_out(stdout: Vec<u8>, stderr: Vec<u8>) -> Self {
+        Self::with_result(ChildResult::TimedOut, stdout, stderr)
+    }
+
+    /// Record that the run wrote more than was kept of its output
+    pub fn truncated(mut self, stdout_bytes: u64, stderr_bytes: u64) -> Self {
+        self.stdout_truncated = stdout_bytes > self.stdout.len() as u64;
+        self.stderr_truncated = stderr_bytes > self.stderr.len() as u64;
+        self.stdout_bytes = stdout_bytes.max(self.stdout.len() as u64);
+        self.stderr_bytes = stderr_bytes.max(self.stderr.len() as u64);
+        self
+    }
+
+    /// Record how many bytes the program wrote to stdout and stderr, kept or
+    /// not, and whether the output limit cut any of them off
+    pub(crate) fn written(mut self, stdout: (u64, bool), stderr: (u64, bool)) -> Self {
+        (self.stdout_bytes, self.stdout_truncated) = stdout;
+        (self.stderr_bytes, self.stderr_truncated) = stderr;
+        self
+    }
+
+    /// Take how the program ended from the exit line of the scripts, which
+    /// tells a signal apart from an exit status of 128 plus it, unlike the
+    /// exit code of the sandbox. A run that timed out stays timed out.
+    pub(crate) fn ended(mut self, exit: &Exit) -> Self {
+        if !self.is_timed_out() {
+            self.result = match exit.signal {
+                Some(signal) => ChildResult::Signal(signal),
+                None => ChildResult::ExitCode(exit.status),
+            };
+        }
+        self
+    }
+
+    /// Record how long the run took, sandbox setup included
+    pub fn timed(mut self, elapsed: Duration) -> Self {
+        self.elapsed = elapsed;
+        self
+    }
+
+    /// How long the run took, sandbox setup included
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Record when the output arrived, by default all of stdout and then all
+    /// of stderr right at the start
+    pub fn with_timeline(mut self, timeline: Vec<Chunk>) -> Self {
+        self.timeline = timeline;
+        self
+    }
+
+    /// The kept output of both streams, in the order it arrived
+    pub fn timeline(&self) -> &[Chunk] {
+        &self.timeline
+    }
+
+    pub fn stdout_truncated(&self) -> bool {
+        self.stdout_truncated
+    }
+
+    pub fn stderr_truncated(&self) -> bool {
+        self.stderr_truncated
+    }
+
+    /// Size of stdout before truncation
+    pub fn stdout_bytes(&self) -> u64 {
+        self.stdout_bytes
+    }
+
+    /// Size of stderr before truncation
+    pub fn stderr_bytes(&self) -> u64 {
+        self.stderr_bytes
+    }
+
+    pub fn stdout(&self) -> &[u8] {
+        self.stdout.as_slice()
+    }
+
+    pub fn stderr(&self) -> &[u8] {
+        self.stderr.as_slice()
+    }
+
+    pub fn success(&self) -> bool {
+        matches!(self.result, ChildResult::ExitCode(0))
+    }
+
+    /// Exit code of a run that exited on its own
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.result {
+            ChildResult::ExitCode(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Signal that terminated the run
+    pub fn signal(&self) -> Option<i32> {
+        match self.result {
+            ChildResult::Signal(signal) => Some(signal),
+            _ => None,
+        }
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        matches!(self.result, ChildResult::TimedOut)
+    }
+
+    /// How the run ended, `failed` being the phase it was in when it did
+    pub fn outcome(&self, failed: Phase) -> Outcome {
+        Outcome {
+            exit_code: self.exit_code(),
+            signal: self.signal(),
+            signal_name: self.signal().and_then(signal_name),
+            timed_out: self.is_timed_out(),
+            failed_phase: (!self.success()).then_some(failed),
+        }
+    }
+}
+
+/// Part of an evaluation a program is in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Compile,
+    Run,
+}
+
+/// How a run ended, in more detail than success or failure
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Outcome {
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// e.g. `SIGSEGV`
+    pub signal_name: Option<&'static str>,
+    pub timed_out: bool,
+    /// the phase that did not succeed, none if everything did
+    pub failed_phase: Option<Phase>,
+}
+
+/// Name of a Linux signal
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    Some(match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGSTKFLT => "SIGSTKFLT",
+        libc::SIGCHLD => "SIGCHLD",
+        libc::SIGCONT => "SIGCONT",
+        libc::SIGSTOP => "SIGSTOP",
+        libc::SIGTSTP => "SIGTSTP",
+        libc::SIGTTIN => "SIGTTIN",
+        libc::SIGTTOU => "SIGTTOU",
+        libc::SIGURG => "SIGURG",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGVTALRM => "SIGVTALRM",
+        libc::SIGPROF => "SIGPROF",
+        libc::SIGWINCH => "SIGWINCH",
+        libc::SIGIO => "SIGIO",
+        libc::SIGPWR => "SIGPWR",
+        libc::SIGSYS => "SIGSYS",
+        _ => return None,
+    })
+}
+
+/// One of the output streams of a run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A piece of output, as it arrived
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub stream: Stream,
+    /// since the sandbox started
+    pub offset: Duration,
+    pub bytes: Vec<u8>,
+}
+
+/// Everything a sandbox needs to know to run one of the in-container scripts
+#[derive(Clone, Debug)]
+pub struct RunRequest {
+    /// unique id of this run, used to tell runs apart in logs and labels
+    pub id: String,
+    pub branch: Branch,
+    pub cmd: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub input: Vec<u8>,
+    pub profile: Profile,
+    pub runtime: Runtime,
+    pub limits: Limits,
+    /// gets the output the result keeps as it arrives, closed when the run is over
+    pub output: Option<UnboundedSender<Chunk>>,
+}
+
+/// Counters of one pool of prepared sandboxes
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct PoolStats {
+    pub image: String,
+    pub cmd: String,
+    pub ready: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A backend that runs commands in isolation from the host
+pub trait Sandbox: Send + Sync {
+    /// Run the requested command to completion, feeding it `request.input` on stdin
+    fn run(&self, request: RunRequest) -> BoxFuture<'_, Result<RunResult>>;
+
+    /// State of the pools of prepared sandboxes, if the backend keeps any
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        vec![]
+    }
+}
+
+/// In-process sandbox that hands out scripted results instead of running anything
+///
+/// Results are returned in the order they were pushed. Clones share their
+/// state, so a test can keep a handle to inspect the requests it received.
+#[derive(Clone, Default)]
+pub struct FakeSandbox {
+    state: Arc<Mutex<FakeState>>,
+}
+
+/// Makes up the result of a run from its request
+type Scripted = Box<dyn FnOnce(&RunRequest) -> RunResult + Send>;
+
+#[derive(Default)]
+struct FakeState {
+    results: VecDeque<Scripted>,
+    requests: Vec<RunRequest>,
+    delay: Duration,
+}
+
+impl FakeSandbox {
+    pub fn new() -> FakeSandbox {
+        FakeSandbox::default()
+    }
+
+    /// Queue up the result for the next run
+    pub fn push(&self, result: RunResult) {
+        self.state
+            .lock()
+            .unwrap()
+            .results
+            .push_back(Box::new(|_| result));
+    }
+
+    /// Queue up a run whose scripts report `transcript`, framed with the token
+    /// of the request it answers, `finish` turning the framed stdout into the
+    /// result
+    pub fn push_transcript(
+        &self,
+        transcript: Transcript,
+        finish: impl FnOnce(Vec<u8>) -> RunResult + Send + 'static,
+    ) {
+        self.state
+            .lock()
+            .unwrap()
+            .results
+            .push_back(Box::new(move |request| {
+                let token = ScriptInput::decode(&request.input)
+                    .map(|input| input.token)
+                    .unwrap_or_default();
+                finish(transcript.encode(&token))
+            }));
+    }
+
+    /// Make every run take `delay` before it returns its result
+    pub fn delay(&self, delay: Duration) {
+        self.state.lock().unwrap().delay = delay;
+    }
+
+    /// All requests received so far
+    pub fn requests(&self) -> Vec<RunRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Sandbox for FakeSandbox {
+    fn run(&self, mut request: RunRequest) -> BoxFuture<'_, Result<RunResult>> {
+        let mut state = self.state.lock().unwrap();
+        let result = state.results.pop_front().map(|scripted| scripted(&request));
+        let cmd = request.cmd.clone();
+        let output = request.output.take();
+        let delay = state.delay;
+        state.requests.push(request);
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            let result =
+                result.ok_or_else(|| anyhow::anyhow!("no scripted result left for {}", cmd))?;
+            if let Some(output) = output {
+                for chunk in &result.timeline {
+                    let _ = output.send(chunk.clone());
+                }
+            }
+            Ok(result)
+        })
+    }
+}
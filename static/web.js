@@ -97,15 +97,119 @@
 
         if (response.status === 200) {
             callback(json);
+        } else {
+            show_failure(result, response, json);
+        }
+    }
+
+    /**
+     * Writes why a request failed to the #result element
+     * @param {HTMLDivElement} result 
+     * @param {Response} response 
+     * @param {any} problem the problem details the response carried, if any
+     * @returns {void}
+     */
+    function show_failure(result, response, problem) {
+        if (response.status === 429) {
+            set_result(result, "<p class=error>Too many requests" +
+                        "<p class=error-explanation>Please wait " +
+                        response.headers.get("Retry-After") + " seconds before trying again.");
+        } else if (problem && problem.code === "queue_full") {
+            set_result(result, "<p class=error>The playground is busy" +
+                        "<p class=error-explanation>Too many programs are waiting to run, please try again in " +
+                        response.headers.get("Retry-After") + " seconds.");
         } else if (response.status === 0) {
             set_result(result, "<p class=error>Connection failure" +
                         "<p class=error-explanation>Are you connected to the Internet?");
+        } else if (problem && (problem.status === 400 || problem.status === 413)) {
+            const p = document.createElement("p");
+            p.className = "error-explanation";
+            p.textContent = problem.detail + ".";
+            set_result(result, "<p class=error>Invalid request");
+            result.appendChild(p);
+        } else if (problem && problem.detail) {
+            const p = document.createElement("p");
+            p.className = "error-explanation";
+            p.textContent = "The playground answered: " + problem.detail + ".";
+            set_result(result, "<p class=error>Something went wrong");
+            result.appendChild(p);
         } else {
             set_result(result, "<p class=error>Something went wrong" +
                         "<p class=error-explanation>The HTTP request produced a response with status code " + response.status + ".");
         }
     }
 
+    /**
+     * Like `send()`, for routes answering with server-sent events
+     * @param {string} path 
+     * @param {any} data 
+     * @param {Function} on_event called with the name and data of every event as it arrives
+     * @param {Function} callback called with the data of the final `outcome` event
+     * @param {HTMLButtonElement} button 
+     * @param {String} message 
+     * @param {HTMLDivElement} result 
+     * @returns {void}
+     */
+    async function send_streaming(path, data, on_event, callback, button, message, result) {
+        let response;
+        button.disabled = true;
+
+        set_result(result, "<p class=message>" + message);
+
+        try {
+            response = await fetch(path, {
+                method: "POST",
+                headers: {
+                    "Content-Type": "application/json",
+                },
+                body: JSON.stringify(data),
+            });
+        } catch(err) {
+            button.disabled = false;
+            set_result(result, "<p class=error>Something went wrong" +
+                    "<p class=error-explanation>The HTTP request produced an error with message " + err.message + ".");
+            return;
+        }
+        if (response.status !== 200) {
+            button.disabled = false;
+            show_failure(result, response, await response.json().catch(() => null));
+            return;
+        }
+
+        const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+        let buffer = "", end;
+        for (;;) {
+            const { value, done } = await reader.read();
+            if (done) {
+                break;
+            }
+            buffer += value;
+            while ((end = buffer.indexOf("\n\n")) >= 0) {
+                let name, payload;
+                for (const line of buffer.slice(0, end).split("\n")) {
+                    if (line.startsWith("event: ")) {
+                        name = line.slice(7);
+                    } else if (line.startsWith("data: ")) {
+                        payload = JSON.parse(line.slice(6));
+                    }
+                }
+                buffer = buffer.slice(end + 2);
+                if (name === "outcome") {
+                    callback(payload);
+                } else if (name === "error" && payload.retry_after) {
+                    set_result(result, "<p class=error>The playground is busy" +
+                        "<p class=error-explanation>Too many programs are waiting to run, please try again in " +
+                        payload.retry_after + " seconds.");
+                } else if (name === "error") {
+                    show_failure(result, response, payload);
+                } else if (name) {
+                    on_event(name, payload);
+                }
+            }
+        }
+        button.disabled = false;
+    }
+
     const PYGMENTS_TO_ACE_MAPPINGS = {
         'asm': {
             'c': 'ace_comment', // Comment,
@@ -162,18 +266,42 @@
     }
 
     /**
-     * Passes the code to `send()` and displays the evaluated code in `#result`
+     * Passes the code to `send_streaming()` and displays the evaluated code in `#result`
      * @param {HTMLDivElement} result 
      * @param {String} code 
      * @param {HTMLButtonElement} button 
      * @return {void}
      */
     function evaluate(result, code, button) {
-        send("/evaluate.json", {
+        let live;
+        send_streaming("/evaluate.sse", {
             code: code,
             separate_output: true,
             color: true,
             branch: branch
+        }, function (name, data) {
+            // the id to cancel the run by, nothing to show
+            if (name === "run") {
+                return;
+            }
+            if (name === "queued") {
+                set_result(result, "<p class=message>Waiting for " +
+                        (data.position === 0 ? "the next free sandbox..." : data.position + " programs ahead in the queue..."));
+                return;
+            }
+            // output as it arrives, replaced by the full result at the end
+            if (!live) {
+                live = document.createElement("pre");
+                live.classList.add("output");
+                set_result(result, live);
+            }
+            const span = document.createElement("span");
+            if (name === "stderr") {
+                span.className = "stderr";
+            }
+            span.textContent = name === "compiler" ? data.output : data.data;
+            live.appendChild(span);
+            set_result.editor.resize();
         }, function (object) {
             let samp, pre, h;
             set_result(result);
@@ -223,12 +351,27 @@
                 result.appendChild(pre);
             }
 
+            if (object.stdout_truncated || object.stderr_truncated) {
+                const truncated = document.createElement("p");
+                truncated.className = "message";
+                truncated.textContent = "Output was cut off, the program wrote too much and was stopped.";
+                result.appendChild(truncated);
+            }
+
             const div = document.createElement("p");
+            const outcome = object.outcome || {};
             div.className = "message";
-            if (object.success && (object.stdout || object.stderr)) {
+            if (outcome.timed_out) {
+                div.textContent = (outcome.failed_phase === "compile" ? "Compilation" : "Program") +
+                    " timed out after " + object.limits.timeout_secs + "s.";
+            } else if (object.success && (object.stdout || object.stderr)) {
                 div.textContent = "Program ended.";
             } else if (object.success) {
                 div.textContent = "Program ended with no output.";
+            } else if (outcome.failed_phase === "run" && outcome.signal_name) {
+                div.textContent = "Program was terminated by " + outcome.signal_name + ".";
+            } else if (outcome.failed_phase === "run") {
+                div.textContent = "Program exited with code " + outcome.exit_code + ".";
             } else {
                 div.textContent = "Compilation failed.";
             }
@@ -0,0 +1,66 @@
+use pony_playground::{Color, Renderer};
+use serde_json::json;
+
+const ERROR: &str = "\x1b[1m\x1b[31mError:\x1b[0m main.pony:3 <expected> & \"found\"\x1b(B\x1b[m\n";
+
+#[test]
+fn colors_as_asked_for() {
+    assert_eq!(ERROR, Color::Ansi.render(ERROR));
+    assert_eq!(
+        "Error: main.pony:3 <expected> & \"found\"\n",
+        Color::None.render(ERROR)
+    );
+    assert_eq!(
+        "<span class=\"ansi-red\"><strong>Error:</strong></span> \
+         main.pony:3 &lt;expected&gt; &amp; &quot;found&quot;\n",
+        Color::Html.render(ERROR)
+    );
+    // 256 colors are dropped, the basic ones after them still count
+    assert_eq!(
+        "<span class=\"ansi-green\">ok</span>",
+        Color::Html.render("\x1b[38;5;208;32mok\x1b[39m")
+    );
+    // parameters that do not parse are skipped instead of resetting
+    assert_eq!(
+        "<span class=\"ansi-green\"><strong>ok</strong></span>",
+        Color::Html.render("\x1b[1;;300;32mok\x1b[0m")
+    );
+    assert_eq!(
+        "<span class=\"ansi-blue\">ok</span>",
+        Color::Html.render("\x1b[34m\x1b[38;5;300mok\x1b[m")
+    );
+}
+
+#[test]
+fn color_in_requests() {
+    for (choice, color) in [
+        (json!(true), Color::Ansi),
+        (json!(false), Color::None),
+        (json!("ansi"), Color::Ansi),
+        (json!("none"), Color::None),
+        (json!("html"), Color::Html),
+    ] {
+        assert_eq!(color, serde_json::from_value(choice).unwrap());
+    }
+    assert!(serde_json::from_value::<Color>(json!("rainbow")).is_err());
+}
+
+#[test]
+fn escape_codes_split_across_chunks() {
+    let mut renderer = Renderer::new(Color::None);
+    assert_eq!("a", renderer.push("a\x1b["));
+    assert_eq!("bc", renderer.push("31mb\x1b[0mc"));
+    assert_eq!("", renderer.finish());
+
+    // every chunk is complete HTML on its own
+    let mut renderer = Renderer::new(Color::Html);
+    assert_eq!(
+        "<span class=\"ansi-cyan\">x</span>",
+        renderer.push("\x1b[36mx")
+    );
+    assert_eq!(
+        "<span class=\"ansi-cyan\">y</span>z",
+        renderer.push("y\x1b[0mz")
+    );
+    assert_eq!("", renderer.finish());
+}
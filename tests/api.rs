@@ -1,6 +1,8 @@
 use anyhow::Result;
 use pony_playground::api::serve;
-use pony_playground::init_github_client;
+use pony_playground::{
+    Branch, BranchConfig, Config, DockerSandbox, Playpen, Profile, init_github_client,
+};
 use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
@@ -19,7 +21,7 @@ async fn evaluate() -> Result<()> {
     let serve_addr = addr;
     let gh_client = init_github_client("FOO".to_string())?;
     let handle: JoinHandle<Result<()>> =
-        tokio::spawn(async move { serve(serve_addr, gh_client).await });
+        tokio::spawn(async move { serve(serve_addr, gh_client, Playpen::default()).await });
     // TODO: how to better ensure the background task is already serving?
     tokio::time::sleep(Duration::from_secs(1)).await;
     let client = Client::new();
@@ -71,7 +73,7 @@ async fn compile() -> Result<()> {
     let serve_addr = addr;
     let gh_client = init_github_client("FOO".to_string())?;
     let handle: JoinHandle<Result<()>> =
-        tokio::spawn(async move { serve(serve_addr, gh_client).await });
+        tokio::spawn(async move { serve(serve_addr, gh_client, Playpen::default()).await });
     // TODO: how to better ensure the background task is already serving?
     tokio::time::sleep(Duration::from_secs(1)).await;
     let client = Client::new();
@@ -157,3 +159,52 @@ struct CompileOutput {
     result: Option<String>,
     error: Option<String>,
 }
+
+/// tries to create a couple of files, printing which ones it could write
+const WRITE_FILES: &str = r#"
+use "files"
+
+actor Main
+  new create(env: Env) =>
+    let auth = FileAuth(env.root)
+    for path in ["workspace-file"; "/tmp/file"; "/file"; "/etc/file"; "/usr/local/bin/file"].values() do
+      let written =
+        match CreateFile(FilePath(auth, path))
+        | let file: File => file.write("x") and file.flush()
+        else false
+        end
+      env.out.print(path + ": " + (if written then "written" else "failed" end))
+    end
+"#;
+
+#[tokio::test]
+async fn hardened_profile_confines_writes() -> Result<()> {
+    let _ = env_logger::try_init();
+    let config = Config {
+        branches: [(
+            Branch::Release,
+            BranchConfig {
+                profile: Profile::Hardened,
+                ..BranchConfig::default()
+            },
+        )]
+        .into(),
+        ..Config::default()
+    };
+    let playpen = Playpen::with_config(DockerSandbox::default(), config);
+
+    let evaluation = playpen
+        .evaluate("test", Branch::Release, WRITE_FILES.to_string())
+        .await?;
+    let (compiler, stdout) = (evaluation.compiler(), evaluation.stdout());
+    assert!(evaluation.result.success(), "{compiler}");
+    let lines: Vec<&str> = stdout.lines().collect();
+    // the workspace and /tmp are tmpfs mounts
+    assert!(lines.contains(&"workspace-file: written"), "{stdout}");
+    assert!(lines.contains(&"/tmp/file: written"), "{stdout}");
+    // the root filesystem is read-only
+    assert!(lines.contains(&"/file: failed"), "{stdout}");
+    assert!(lines.contains(&"/etc/file: failed"), "{stdout}");
+    assert!(lines.contains(&"/usr/local/bin/file: failed"), "{stdout}");
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use pony_playground::{Branch, Config, Limits, Profile, RateLimit, Runtime};
+use std::path::Path;
+use std::time::Duration;
+
+#[test]
+fn example_config_matches_defaults() -> Result<()> {
+    let config = Config::load(Path::new("playpen.example.toml"))?;
+    assert_eq!(Limits::default(), config.branch(Branch::Release).limits);
+    assert_eq!(Limits::default(), config.branch(Branch::Regions).limits);
+    assert_eq!(Profile::Standard, config.branch(Branch::Release).profile);
+    assert_eq!(Runtime::default(), config.branch(Branch::Release).runtime);
+    assert!(config.server.trusted_proxies.is_empty());
+    assert_eq!(2 * 1024 * 1024, config.server.max_body_bytes);
+    let defaults = Config::default().rate_limit;
+    assert_eq!(defaults.evaluate, config.rate_limit.evaluate);
+    assert_eq!(defaults.compile, config.rate_limit.compile);
+    assert_eq!(defaults.jobs, config.rate_limit.jobs);
+    assert_eq!(defaults.batch, config.rate_limit.batch);
+    assert_eq!(
+        RateLimit {
+            burst: 5,
+            per_minute: 2
+        },
+        config.rate_limit.gist
+    );
+    assert_eq!(8, config.queue.max_running);
+    assert_eq!(64, config.queue.max_queued);
+    assert_eq!(Duration::from_secs(5), config.queue.retry_after);
+    assert_eq!(2, config.pool.size);
+    assert_eq!(Duration::from_secs(900), config.pool.idle_timeout);
+    assert_eq!(Duration::from_secs(600), config.jobs.retention);
+    assert_eq!(200, config.batch.max_snippets);
+    assert_eq!(4, config.batch.parallelism);
+    Ok(())
+}
+
+#[test]
+fn branch_limits() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("playpen.toml");
+    std::fs::write(
+        &path,
+        r#"
+[branches.regions]
+profile = "hardened"
+
+[branches.regions.runtime]
+oci_runtime = "runsc"
+
+[branches.regions.limits]
+memory_mb = 512
+cpu_quota_us = 25000
+timeout_secs = 30
+ulimits = [{ name = "nofile", soft = 64, hard = 64 }]
+"#,
+    )?;
+    let config = Config::load(&path)?;
+    assert_eq!(Profile::Hardened, config.branch(Branch::Regions).profile);
+    let runtime = config.branch(Branch::Regions).runtime;
+    assert_eq!(Some("runsc".to_string()), runtime.oci_runtime);
+    assert_eq!(None, runtime.socket);
+    let limits = config.branch(Branch::Regions).limits;
+    assert_eq!(512, limits.memory_mb);
+    assert_eq!(25_000, limits.cpu_quota_us);
+    assert_eq!(Duration::from_secs(30), limits.timeout);
+    assert_eq!("nofile", limits.ulimits[0].name);
+    // unset values keep their defaults
+    assert_eq!(20, limits.pids);
+    assert_eq!(Limits::default(), config.branch(Branch::Release).limits);
+    Ok(())
+}
+
+#[test]
+fn unknown_settings_are_rejected() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("playpen.toml");
+    std::fs::write(&path, "[branches.release.limits]\nmemory = 512\n")?;
+    assert!(Config::load(&path).is_err());
+    std::fs::write(&path, "[branches.relase.limits]\nmemory_mb = 512\n")?;
+    assert!(Config::load(&path).is_err());
+    Ok(())
+}
@@ -0,0 +1,928 @@
+//! Runs the docker sandbox against a stub of the Docker Engine API listening on a unix socket
+
+use anyhow::Result;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use pony_playground::{
+    Branch, BranchConfig, Compilation, CompileOutput, Config, DockerSandbox, Engine, EngineError,
+    Evaluation, Exit, Limits, Phase, Playpen, PoolConfig, Profile, ReaperConfig, RunRequest,
+    Runtime, Sandbox, ScriptInput, Stream, Transcript, Ulimit,
+};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixListener;
+use tokio::sync::watch;
+
+/// A docker daemon running fake containers, with ids `c0`, `c1` and so on
+///
+/// Each container plays the scripts: given code, it answers with `ponyc` as
+/// the compiler's output, `got: <code>` as the program's and `oops` on
+/// stderr, and exits with 3. If the code is `loop` it never exits on its
+/// own, if it is `flood` it writes 3000 bytes to stdout and then never exits
+/// on its own.
+struct Stub {
+    missing_image: bool,
+    image_id: Mutex<String>,
+    calls: Mutex<Vec<String>>,
+    created: Mutex<Vec<Value>>,
+    containers: Mutex<HashMap<String, FakeContainer>>,
+}
+
+struct FakeContainer {
+    labels: Value,
+    /// unix timestamp
+    created: i64,
+    /// unix timestamp, none until started
+    started: Option<i64>,
+    exited: watch::Sender<Option<i64>>,
+}
+
+/// unix timestamp of `age` ago
+fn ago(age: Duration) -> i64 {
+    (SystemTime::now().duration_since(UNIX_EPOCH).unwrap() - age).as_secs() as i64
+}
+
+impl Stub {
+    fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn exited(&self, id: &str) -> Option<watch::Sender<Option<i64>>> {
+        self.containers
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|container| container.exited.clone())
+    }
+
+    /// a container created `age` ago, and started `started` ago if it was
+    fn add_container(&self, id: &str, labels: Value, age: Duration, started: Option<Duration>) {
+        self.containers.lock().unwrap().insert(
+            id.to_string(),
+            FakeContainer {
+                labels,
+                created: ago(age),
+                started: started.map(ago),
+                exited: watch::channel(None).0,
+            },
+        );
+    }
+}
+
+async fn start_stub(missing_image: bool) -> Result<(Arc<Stub>, tempfile::TempDir, PathBuf)> {
+    let _ = env_logger::try_init();
+    let dir = tempfile::tempdir()?;
+    let socket = dir.path().join("docker.sock");
+    let listener = UnixListener::bind(&socket)?;
+    let stub = Arc::new(Stub {
+        missing_image,
+        image_id: Mutex::new("sha256:1".to_string()),
+        calls: Mutex::new(vec![]),
+        created: Mutex::new(vec![]),
+        containers: Mutex::new(HashMap::new()),
+    });
+    let server_stub = stub.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stub = server_stub.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| handle(stub.clone(), req));
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .with_upgrades()
+                    .await;
+            });
+        }
+    });
+    Ok((stub, dir, socket))
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+fn frame(stream: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![stream, 0, 0, 0];
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+async fn handle(
+    stub: Arc<Stub>,
+    mut req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    stub.calls
+        .lock()
+        .unwrap()
+        .push(format!("{} {}", method, path));
+    let segments: Vec<&str> = path.split('/').skip(2).collect();
+    let no_such_container = |id: &str| {
+        respond(
+            StatusCode::NOT_FOUND,
+            json!({"message": format!("No such container: {}", id)}),
+        )
+    };
+    let res = match (method, segments.as_slice()) {
+        (Method::GET, ["containers", "json"]) => {
+            let containers = stub.containers.lock().unwrap();
+            let list: Vec<Value> = containers
+                .iter()
+                .filter(|(_, container)| container.labels.get("io.ponylang.playpen").is_some())
+                .map(|(id, container)| {
+                    let state = if container.exited.borrow().is_some() {
+                        "exited"
+                    } else if container.started.is_some() {
+                        "running"
+                    } else {
+                        "created"
+                    };
+                    json!({
+                        "Id": id,
+                        "Labels": container.labels,
+                        "Created": container.created,
+                        "State": state,
+                    })
+                })
+                .collect();
+            respond(StatusCode::OK, json!(list))
+        }
+        (Method::GET, ["containers", id, "json"]) => {
+            match stub.containers.lock().unwrap().get(*id) {
+                Some(container) => {
+                    let started_at = container
+                        .started
+                        .map(|started| chrono::DateTime::from_timestamp(started, 0).unwrap())
+                        .unwrap_or_default();
+                    respond(
+                        StatusCode::OK,
+                        json!({"Id": id, "State": {"StartedAt": started_at.to_rfc3339()}}),
+                    )
+                }
+                None => no_such_container(id),
+            }
+        }
+        (Method::GET, ["info"]) => respond(
+            StatusCode::OK,
+            json!({
+                "Name": "stub",
+                "ServerVersion": "1.0",
+                "Runtimes": {"runc": {"path": "runc"}},
+                "DefaultRuntime": "runc",
+            }),
+        ),
+        (Method::GET, ["images", .., "json"]) => respond(
+            StatusCode::OK,
+            json!({"Id": *stub.image_id.lock().unwrap()}),
+        ),
+        (Method::POST, ["containers", "create"]) => {
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            let config: Value = serde_json::from_slice(&body).unwrap();
+            let image = config["Image"].as_str().unwrap_or_default().to_string();
+            let labels = config["Labels"].clone();
+            stub.created.lock().unwrap().push(config);
+            if stub.missing_image {
+                respond(
+                    StatusCode::NOT_FOUND,
+                    json!({"message": format!("No such image: {}", image)}),
+                )
+            } else {
+                let id = format!("c{}", stub.created.lock().unwrap().len() - 1);
+                stub.add_container(&id, labels, Duration::ZERO, None);
+                respond(StatusCode::CREATED, json!({"Id": id, "Warnings": []}))
+            }
+        }
+        (Method::POST, ["containers", id, "attach"]) => match stub.exited(id) {
+            Some(exited) => {
+                let upgrade = hyper::upgrade::on(&mut req);
+                tokio::spawn(async move {
+                    let mut io = TokioIo::new(upgrade.await.unwrap());
+                    let mut input = vec![];
+                    io.read_to_end(&mut input).await.unwrap();
+                    let script = ScriptInput::decode(&input);
+                    let input = match &script {
+                        Some(script) => script.code.as_bytes().to_vec(),
+                        None => input,
+                    };
+                    if input == b"flood" {
+                        for _ in 0..3 {
+                            io.write_all(&frame(1, &[b'x'; 1000])).await.unwrap();
+                        }
+                    }
+                    if input == b"loop" || input == b"flood" {
+                        let _ = exited.subscribe().wait_for(|code| code.is_some()).await;
+                    } else {
+                        let mut stdout = b"got: ".to_vec();
+                        stdout.extend_from_slice(&input);
+                        let transcript = Transcript {
+                            compiler: Some(Compilation {
+                                stdout: b"ponyc".to_vec(),
+                                ..Compilation::default()
+                            }),
+                            stdout,
+                            exit: Some(Exit {
+                                status: 3,
+                                ..Exit::default()
+                            }),
+                        };
+                        let token = script.map(|script| script.token).unwrap_or_default();
+                        io.write_all(&frame(1, &transcript.encode(&token)))
+                            .await
+                            .unwrap();
+                        io.write_all(&frame(2, b"oops")).await.unwrap();
+                        exited.send_replace(Some(3));
+                    }
+                    io.shutdown().await.unwrap();
+                });
+                Response::builder()
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header("connection", "Upgrade")
+                    .header("upgrade", "tcp")
+                    .body(Full::default())
+                    .unwrap()
+            }
+            None => no_such_container(id),
+        },
+        (Method::POST, ["containers", id, "start"]) => {
+            match stub.containers.lock().unwrap().get_mut(*id) {
+                Some(container) => {
+                    container.started = Some(ago(Duration::ZERO));
+                    respond(StatusCode::NO_CONTENT, Value::Null)
+                }
+                None => no_such_container(id),
+            }
+        }
+        (Method::POST, ["containers", id, "wait"]) => match stub.exited(id) {
+            Some(exited) => {
+                let code = *exited
+                    .subscribe()
+                    .wait_for(|code| code.is_some())
+                    .await
+                    .unwrap();
+                respond(StatusCode::OK, json!({"StatusCode": code}))
+            }
+            None => no_such_container(id),
+        },
+        (Method::POST, ["containers", id, "kill"]) => match stub.exited(id) {
+            Some(exited) => {
+                exited.send_replace(Some(137));
+                respond(StatusCode::NO_CONTENT, Value::Null)
+            }
+            None => no_such_container(id),
+        },
+        (Method::DELETE, ["containers", id]) => match stub.containers.lock().unwrap().remove(*id) {
+            Some(container) => {
+                container.exited.send_replace(Some(137));
+                respond(StatusCode::NO_CONTENT, Value::Null)
+            }
+            None => no_such_container(id),
+        },
+        _ => respond(
+            StatusCode::NOT_FOUND,
+            json!({"message": format!("page not found: {}", path)}),
+        ),
+    };
+    Ok(res)
+}
+
+/// wait up to a second for `condition` to hold
+async fn eventually(what: &str, condition: impl Fn() -> bool) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out waiting for {}", what);
+}
+
+/// removal happens in the background once the container is dropped
+async fn wait_for_removal(stub: &Stub) {
+    eventually("container removal", || {
+        stub.calls().iter().any(|call| call.starts_with("DELETE"))
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn evaluate_through_engine_api() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let sandbox = DockerSandbox::new(Engine::new(socket));
+    let instance = sandbox.instance().to_string();
+    let playpen = Playpen::new(sandbox);
+
+    let evaluation = playpen
+        .evaluate("test", Branch::Release, "actor Main".to_string())
+        .await?;
+    let (compiler, output) = (evaluation.compiler(), evaluation.stdout());
+    let Evaluation {
+        result,
+        transcript,
+        outcome,
+        ..
+    } = evaluation;
+    assert!(!result.success());
+    assert_eq!(Some(3), outcome.exit_code);
+    assert_eq!(Some(Phase::Run), outcome.failed_phase);
+    assert_eq!("ponyc", compiler);
+    assert_eq!("got: actor Main", output);
+    assert_eq!(Some(3), transcript.exit.map(|exit| exit.status));
+    assert!(result.elapsed() > Duration::ZERO);
+    assert_eq!(b"oops", result.stderr());
+
+    wait_for_removal(&stub).await;
+    assert_eq!(
+        vec![
+            "POST /v1.41/containers/create",
+            "POST /v1.41/containers/c0/attach",
+            "POST /v1.41/containers/c0/start",
+            "POST /v1.41/containers/c0/wait",
+            "DELETE /v1.41/containers/c0",
+        ],
+        stub.calls()
+    );
+    let created = stub.created.lock().unwrap()[0].clone();
+    assert_eq!("ponylang-playpen:latest", created["Image"]);
+    assert_eq!("1", created["Labels"]["io.ponylang.playpen"]);
+    assert_eq!(instance, created["Labels"]["io.ponylang.playpen.instance"]);
+    assert_eq!("release", created["Labels"]["io.ponylang.playpen.branch"]);
+    assert_eq!(
+        32,
+        created["Labels"]["io.ponylang.playpen.request"]
+            .as_str()
+            .unwrap()
+            .len()
+    );
+    assert_eq!(json!(["/usr/local/bin/evaluate.sh"]), created["Cmd"]);
+    assert_eq!(json!(["ALL"]), created["HostConfig"]["CapDrop"]);
+    assert_eq!("none", created["HostConfig"]["NetworkMode"]);
+    assert_eq!(20, created["HostConfig"]["PidsLimit"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn branch_limits_are_enforced() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let limits = Limits {
+        memory_mb: 256,
+        swap_mb: Some(0),
+        cpu_quota_us: 50_000,
+        cpu_shares: 512,
+        pids: 8,
+        ulimits: vec![Ulimit {
+            name: "nofile".to_string(),
+            soft: 64,
+            hard: 128,
+        }],
+        ..Limits::default()
+    };
+    let config = Config {
+        branches: [(
+            Branch::Regions,
+            BranchConfig {
+                limits: limits.clone(),
+                ..BranchConfig::default()
+            },
+        )]
+        .into(),
+        ..Config::default()
+    };
+    let playpen = Playpen::with_config(DockerSandbox::new(Engine::new(socket)), config);
+    playpen
+        .evaluate("test", Branch::Regions, "actor Main".to_string())
+        .await?;
+    playpen
+        .evaluate("test", Branch::Release, "actor Main".to_string())
+        .await?;
+
+    let created = stub.created.lock().unwrap().clone();
+    let host_config = &created[0]["HostConfig"];
+    assert_eq!(256 * 1024 * 1024, host_config["Memory"]);
+    assert_eq!(256 * 1024 * 1024, host_config["MemorySwap"]);
+    assert_eq!(100_000, host_config["CpuPeriod"]);
+    assert_eq!(50_000, host_config["CpuQuota"]);
+    assert_eq!(512, host_config["CpuShares"]);
+    assert_eq!(8, host_config["PidsLimit"]);
+    assert_eq!(
+        json!([{"Name": "nofile", "Soft": 64, "Hard": 128}]),
+        host_config["Ulimits"]
+    );
+    // branches without a section of their own get the defaults
+    let host_config = &created[1]["HostConfig"];
+    assert_eq!(1024 * 1024 * 1024, host_config["Memory"]);
+    assert!(host_config.get("MemorySwap").is_none());
+    assert_eq!(100_000, host_config["CpuQuota"]);
+    assert_eq!(20, host_config["PidsLimit"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn hardened_profile() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let config = Config {
+        branches: [(
+            Branch::Regions,
+            BranchConfig {
+                profile: Profile::Hardened,
+                limits: Limits {
+                    workspace_mb: 32,
+                    ..Limits::default()
+                },
+                ..BranchConfig::default()
+            },
+        )]
+        .into(),
+        ..Config::default()
+    };
+    let playpen = Playpen::with_config(DockerSandbox::new(Engine::new(socket)), config);
+    playpen
+        .evaluate("test", Branch::Regions, "actor Main".to_string())
+        .await?;
+    playpen
+        .evaluate("test", Branch::Release, "actor Main".to_string())
+        .await?;
+
+    let created = stub.created.lock().unwrap().clone();
+    let hardened = &created[0];
+    assert_eq!("65534:65534", hardened["User"]);
+    let env = hardened["Env"].as_array().unwrap();
+    assert!(env.contains(&json!("TMPDIR=/workspace")));
+    let host_config = &hardened["HostConfig"];
+    assert_eq!(true, host_config["ReadonlyRootfs"]);
+    assert_eq!(
+        "rw,nosuid,nodev,size=32m,mode=1777",
+        host_config["Tmpfs"]["/workspace"]
+    );
+    assert_eq!(
+        "rw,noexec,nosuid,nodev,size=16m,mode=1777",
+        host_config["Tmpfs"]["/tmp"]
+    );
+    let security_opt: Vec<&str> = host_config["SecurityOpt"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|opt| opt.as_str().unwrap())
+        .collect();
+    assert!(security_opt.contains(&"no-new-privileges"));
+    let seccomp = security_opt
+        .iter()
+        .find_map(|opt| opt.strip_prefix("seccomp="))
+        .expect("seccomp profile");
+    let seccomp: Value = serde_json::from_str(seccomp)?;
+    assert_eq!("SCMP_ACT_ERRNO", seccomp["defaultAction"]);
+
+    // the standard profile leaves the root filesystem writable
+    let standard = &created[1];
+    assert!(standard.get("User").is_none());
+    assert_eq!(false, standard["HostConfig"]["ReadonlyRootfs"]);
+    assert!(standard["HostConfig"].get("Tmpfs").is_none());
+    assert_eq!(
+        json!(["no-new-privileges"]),
+        standard["HostConfig"]["SecurityOpt"]
+    );
+    Ok(())
+}
+
+fn runtime_config(branch: Branch, runtime: Runtime) -> Config {
+    Config {
+        branches: [(
+            branch,
+            BranchConfig {
+                runtime,
+                ..BranchConfig::default()
+            },
+        )]
+        .into(),
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn oci_runtime_is_passed_through() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let config = runtime_config(
+        Branch::Regions,
+        Runtime {
+            oci_runtime: Some("runsc".to_string()),
+            ..Runtime::default()
+        },
+    );
+    let playpen = Playpen::with_config(DockerSandbox::new(Engine::new(socket)), config);
+    playpen
+        .evaluate("test", Branch::Regions, "actor Main".to_string())
+        .await?;
+    playpen
+        .evaluate("test", Branch::Release, "actor Main".to_string())
+        .await?;
+
+    let created = stub.created.lock().unwrap().clone();
+    assert_eq!("runsc", created[0]["HostConfig"]["Runtime"]);
+    assert!(created[1]["HostConfig"].get("Runtime").is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn branch_on_another_engine() -> Result<()> {
+    let (docker, _docker_dir, docker_socket) = start_stub(false).await?;
+    let (podman, _podman_dir, podman_socket) = start_stub(false).await?;
+    let config = runtime_config(
+        Branch::Regions,
+        Runtime {
+            socket: Some(podman_socket.clone()),
+            ..Runtime::default()
+        },
+    );
+    let sandbox = DockerSandbox::new(Engine::new(&docker_socket));
+    let engines: Vec<_> = sandbox
+        .engines(&config)
+        .iter()
+        .map(|engine| engine.socket().to_path_buf())
+        .collect();
+    assert_eq!(vec![docker_socket, podman_socket], engines);
+
+    let playpen = Playpen::with_config(sandbox, config);
+    let evaluation = playpen
+        .evaluate("test", Branch::Regions, "actor Main".to_string())
+        .await?;
+    let (result, output) = (&evaluation.result, evaluation.stdout());
+    assert_eq!("got: actor Main", output);
+    assert_eq!(b"oops", result.stderr());
+    assert_eq!(1, podman.created.lock().unwrap().len());
+    assert!(docker.created.lock().unwrap().is_empty());
+    wait_for_removal(&podman).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn missing_runtimes_are_detected() -> Result<()> {
+    let (_stub, dir, socket) = start_stub(false).await?;
+    let sandbox = DockerSandbox::new(Engine::new(&socket));
+
+    let runc = runtime_config(
+        Branch::Regions,
+        Runtime {
+            oci_runtime: Some("runc".to_string()),
+            ..Runtime::default()
+        },
+    );
+    sandbox.check_runtimes(&runc).await?;
+
+    let runsc = runtime_config(
+        Branch::Regions,
+        Runtime {
+            oci_runtime: Some("runsc".to_string()),
+            ..Runtime::default()
+        },
+    );
+    let message = sandbox
+        .check_runtimes(&runsc)
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(message.contains("branch regions"), "{message}");
+    assert!(message.contains("`runsc`"), "{message}");
+    assert!(message.contains("only runc"), "{message}");
+    assert!(!message.contains("branch release"), "{message}");
+
+    let podman = dir.path().join("podman.sock");
+    let no_podman = runtime_config(
+        Branch::Release,
+        Runtime {
+            socket: Some(podman.clone()),
+            ..Runtime::default()
+        },
+    );
+    let message = sandbox
+        .check_runtimes(&no_podman)
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(message.contains("branch release"), "{message}");
+    assert!(message.contains(&podman.display().to_string()), "{message}");
+    assert!(message.contains("podman.socket"), "{message}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn kill_on_timeout() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let sandbox = DockerSandbox::new(Engine::new(socket));
+
+    let result = sandbox
+        .run(RunRequest {
+            id: "r1".to_string(),
+            branch: Branch::Release,
+            cmd: "/usr/local/bin/evaluate.sh".to_string(),
+            args: vec![],
+            env: vec![],
+            input: b"loop".to_vec(),
+            profile: Profile::Standard,
+            runtime: Runtime::default(),
+            limits: Limits {
+                timeout: Duration::from_millis(200),
+                ..Limits::default()
+            },
+            output: None,
+        })
+        .await?;
+    assert!(!result.success());
+    assert!(
+        stub.calls()
+            .contains(&"POST /v1.41/containers/c0/kill".to_string())
+    );
+    wait_for_removal(&stub).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn kill_on_too_much_output() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let sandbox = DockerSandbox::new(Engine::new(socket));
+    let (output, mut chunks) = tokio::sync::mpsc::unbounded_channel();
+
+    let result = sandbox
+        .run(RunRequest {
+            id: "r1".to_string(),
+            branch: Branch::Release,
+            cmd: "/usr/local/bin/evaluate.sh".to_string(),
+            args: vec![],
+            env: vec![],
+            input: b"flood".to_vec(),
+            profile: Profile::Standard,
+            runtime: Runtime::default(),
+            limits: Limits {
+                max_stdout_bytes: 1500,
+                ..Limits::default()
+            },
+            output: Some(output),
+        })
+        .await?;
+    assert!(!result.success());
+    // killed, which docker reports as exit code 137
+    assert_eq!(Some(9), result.signal());
+    assert!(result.stdout_truncated());
+    assert!(!result.stderr_truncated());
+    assert_eq!(1500, result.stdout().len());
+    assert_eq!(3000, result.stdout_bytes());
+    // only what was kept was passed on while running
+    let mut streamed = vec![];
+    while let Some(chunk) = chunks.recv().await {
+        assert_eq!(Stream::Stdout, chunk.stream);
+        streamed.push(chunk);
+    }
+    let bytes: Vec<u8> = streamed
+        .iter()
+        .flat_map(|chunk| chunk.bytes.clone())
+        .collect();
+    assert_eq!(result.stdout(), bytes.as_slice());
+    // and recorded in the same order
+    assert_eq!(streamed.as_slice(), result.timeline());
+    assert!(streamed.is_sorted_by_key(|chunk| chunk.offset));
+    assert!(
+        stub.calls()
+            .contains(&"POST /v1.41/containers/c0/kill".to_string())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn only_the_programs_output_counts_towards_its_limit() -> Result<()> {
+    let (_stub, _dir, socket) = start_stub(false).await?;
+    // just enough for `got: abc`, none of the frames around it
+    let limits = Limits {
+        max_stdout_bytes: 8,
+        max_compile_output_bytes: 4,
+        ..Limits::default()
+    };
+    let config = Config {
+        branches: [(
+            Branch::Release,
+            BranchConfig {
+                limits,
+                ..BranchConfig::default()
+            },
+        )]
+        .into(),
+        ..Config::default()
+    };
+    let playpen = Playpen::with_config(DockerSandbox::new(Engine::new(socket)), config);
+
+    let evaluation = playpen
+        .evaluate("test", Branch::Release, "abc".to_string())
+        .await?;
+    assert!(!evaluation.result.stdout_truncated());
+    assert_eq!(8, evaluation.stdout_bytes());
+    assert_eq!("got: abc", evaluation.stdout());
+    assert_eq!(Some(3), evaluation.result.exit_code());
+
+    // compiling has a limit of its own
+    let (result, transcript) = playpen
+        .compile(
+            "test",
+            Branch::Release,
+            "abc".to_string(),
+            CompileOutput::Asm,
+        )
+        .await?;
+    assert!(result.stdout_truncated());
+    assert_eq!(8, result.stdout_bytes());
+    assert_eq!(b"got:", transcript.stdout.as_slice());
+    Ok(())
+}
+
+#[tokio::test]
+async fn missing_image_is_a_typed_error() -> Result<()> {
+    let (_stub, _dir, socket) = start_stub(true).await?;
+    let playpen = Playpen::new(DockerSandbox::new(Engine::new(socket)));
+
+    let err = playpen
+        .evaluate("test", Branch::Regions, "actor Main".to_string())
+        .await
+        .unwrap_err();
+    let err = err.downcast_ref::<EngineError>().expect("an engine error");
+    assert!(err.is_not_found());
+    assert!(
+        err.to_string()
+            .contains("No such image: plietar/ponylang-playpen:regions")
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn unreachable_socket_is_a_typed_error() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let playpen = Playpen::new(DockerSandbox::new(Engine::new(
+        dir.path().join("nope.sock"),
+    )));
+
+    let err = playpen
+        .evaluate("test", Branch::Release, "actor Main".to_string())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<EngineError>(),
+        Some(EngineError::Connect { .. })
+    ));
+    Ok(())
+}
+
+fn pool_config(idle_timeout: Duration) -> PoolConfig {
+    PoolConfig {
+        size: 2,
+        idle_timeout,
+        check_interval: Duration::from_millis(50),
+    }
+}
+
+#[tokio::test]
+async fn pool_hands_out_prepared_containers() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let playpen = Playpen::new(DockerSandbox::with_pool(
+        Engine::new(socket),
+        pool_config(Duration::from_secs(60)),
+    ));
+
+    // the first request for a branch is a miss and starts filling the pool
+    let output = playpen
+        .evaluate("test", Branch::Release, "first".to_string())
+        .await?
+        .stdout();
+    assert_eq!("got: first", output);
+    eventually("a full pool", || playpen.stats().pools[0].ready == 2).await;
+
+    let output = playpen
+        .evaluate("test", Branch::Release, "second".to_string())
+        .await?
+        .stdout();
+    assert_eq!("got: second", output);
+    let stats = playpen.stats();
+    assert_eq!(1, stats.pools.len());
+    assert_eq!("ponylang-playpen:latest", stats.pools[0].image);
+    assert_eq!("/usr/local/bin/evaluate.sh", stats.pools[0].cmd);
+    assert_eq!(1, stats.pools[0].hits);
+    assert_eq!(1, stats.pools[0].misses);
+    // the second request was served by the first pooled container
+    assert!(
+        stub.calls()
+            .contains(&"POST /v1.41/containers/c1/start".to_string())
+    );
+    eventually("a refilled pool", || playpen.stats().pools[0].ready == 2).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn pool_rebuilds_on_image_change() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let playpen = Playpen::new(DockerSandbox::with_pool(
+        Engine::new(socket),
+        pool_config(Duration::from_secs(60)),
+    ));
+    playpen
+        .evaluate("test", Branch::Release, "first".to_string())
+        .await?;
+    eventually("a full pool", || playpen.stats().pools[0].ready == 2).await;
+    // give the pool a chance to learn the current image id
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    *stub.image_id.lock().unwrap() = "sha256:2".to_string();
+    eventually("removal of outdated containers", || {
+        let calls = stub.calls();
+        calls.contains(&"DELETE /v1.41/containers/c1".to_string())
+            && calls.contains(&"DELETE /v1.41/containers/c2".to_string())
+    })
+    .await;
+    eventually("a rebuilt pool", || playpen.stats().pools[0].ready == 2).await;
+    assert_eq!(5, stub.created.lock().unwrap().len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn pool_shrinks_when_idle() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let playpen = Playpen::new(DockerSandbox::with_pool(
+        Engine::new(socket),
+        pool_config(Duration::from_millis(300)),
+    ));
+    playpen
+        .evaluate("test", Branch::Release, "first".to_string())
+        .await?;
+    eventually("a full pool", || playpen.stats().pools[0].ready == 2).await;
+
+    eventually("an empty pool", || playpen.stats().pools.is_empty()).await;
+    eventually("removal of idle containers", || {
+        let calls = stub.calls();
+        calls.contains(&"DELETE /v1.41/containers/c1".to_string())
+            && calls.contains(&"DELETE /v1.41/containers/c2".to_string())
+    })
+    .await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn reaper_removes_leftovers() -> Result<()> {
+    let (stub, _dir, socket) = start_stub(false).await?;
+    let sandbox =
+        DockerSandbox::with_pool(Engine::new(socket), pool_config(Duration::from_secs(60)));
+    let ours = json!({
+        "io.ponylang.playpen": "1",
+        "io.ponylang.playpen.instance": sandbox.instance(),
+    });
+    let theirs = json!({
+        "io.ponylang.playpen": "1",
+        "io.ponylang.playpen.instance": "crashed",
+    });
+    let hour = Duration::from_secs(3600);
+    let young = Duration::from_secs(5);
+    // left behind by an earlier instance
+    stub.add_container("crashed", theirs.clone(), hour, Some(hour));
+    // another instance's, running for a moment
+    stub.add_container("theirs", theirs, young, Some(young));
+    // ours, but running for way too long
+    stub.add_container("stuck", ours.clone(), hour, Some(hour));
+    // ours and old, but waiting in the pool
+    stub.add_container("pooled", ours.clone(), hour, None);
+    // ours, waited in the pool for long and just handed out
+    stub.add_container("checked-out", ours.clone(), hour, Some(young));
+    // ours and young
+    stub.add_container("running", ours, young, Some(young));
+    // not a playground container at all
+    stub.add_container("unrelated", json!({}), hour, Some(hour));
+
+    let reaper = sandbox.spawn_reaper(ReaperConfig {
+        interval: Duration::from_millis(50),
+        max_lifetime: Duration::from_secs(60),
+    });
+    eventually("removal of leftovers", || {
+        let calls = stub.calls();
+        calls.contains(&"DELETE /v1.41/containers/crashed".to_string())
+            && calls.contains(&"DELETE /v1.41/containers/stuck".to_string())
+    })
+    .await;
+    // give it a couple more rounds to remove anything it shouldn't
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    reaper.abort();
+
+    let mut left: Vec<String> = stub.containers.lock().unwrap().keys().cloned().collect();
+    left.sort();
+    assert_eq!(
+        vec!["checked-out", "pooled", "running", "theirs", "unrelated"],
+        left
+    );
+    Ok(())
+}
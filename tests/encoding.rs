@@ -0,0 +1,42 @@
+use pony_playground::{ByteRange, Encoded, Encoding};
+
+#[test]
+fn valid_output_is_the_same_either_way() {
+    for encoding in [Encoding::Utf8, Encoding::Utf8Ranges] {
+        let encoded = encoding.encode("héllo\n".as_bytes());
+        assert_eq!("héllo\n", encoded.text);
+        assert!(!encoded.lossy);
+    }
+    assert_eq!(None, Encoding::Utf8.encode(b"hello").invalid);
+    assert_eq!(Some(vec![]), Encoding::Utf8Ranges.encode(b"hello").invalid);
+}
+
+#[test]
+fn invalid_bytes() {
+    let output = b"a\xffb\xe2\x82c\x80\x80";
+    assert_eq!(
+        Encoded {
+            text: "a\u{fffd}b\u{fffd}c\u{fffd}\u{fffd}".to_string(),
+            lossy: true,
+            invalid: None,
+        },
+        Encoding::Utf8.encode(output)
+    );
+    assert_eq!(
+        Some(vec![
+            ByteRange { start: 1, end: 2 },
+            ByteRange { start: 3, end: 5 },
+            ByteRange { start: 6, end: 7 },
+            ByteRange { start: 7, end: 8 },
+        ]),
+        Encoding::Utf8Ranges.encode(output).invalid
+    );
+    assert_eq!(
+        Encoded {
+            text: "Yf9i4oJjgIA=".to_string(),
+            lossy: false,
+            invalid: None,
+        },
+        Encoding::Base64.encode(output)
+    );
+}
@@ -0,0 +1,178 @@
+use pony_playground::{Compilation, Decoder, Event, Exit, ScriptInput, Transcript, Usage};
+use std::time::Duration;
+
+const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+fn transcript(program: &[u8]) -> Transcript {
+    Transcript {
+        compiler: Some(Compilation {
+            status: 0,
+            elapsed: Duration::from_millis(1230),
+            stdout: b"Compiled with: LLVM\n".to_vec(),
+            stderr: b"Warning: \xff\n".to_vec(),
+            usage: Usage {
+                cpu_user_us: Some(1_100_000),
+                cpu_system_us: Some(90_000),
+                peak_memory_bytes: Some(250_000_000),
+            },
+        }),
+        stdout: program.to_vec(),
+        exit: Some(Exit {
+            status: 139,
+            signal: Some(11),
+            elapsed: Duration::from_millis(20),
+            // under gVisor
+            usage: Usage::default(),
+        }),
+    }
+}
+
+#[test]
+fn input_round_trip() {
+    let input = ScriptInput {
+        token: TOKEN.to_string(),
+        code: "actor Main\n\n  new create(env: Env) => None\n".to_string(),
+        stdin: vec![],
+        args: vec![],
+        env: vec![],
+    };
+    let encoded = input.encode();
+    assert!(encoded.starts_with(format!("token {TOKEN}\n\n").as_bytes()));
+    assert_eq!(Some(input.clone()), ScriptInput::decode(&encoded));
+
+    let input = ScriptInput {
+        stdin: b"line\n\n\xff".to_vec(),
+        ..input
+    };
+    let encoded = input.encode();
+    assert!(encoded.starts_with(format!("token {TOKEN}\nstdin bGluZQoK/w==\n\n").as_bytes()));
+    assert_eq!(Some(input.clone()), ScriptInput::decode(&encoded));
+
+    let input = ScriptInput {
+        stdin: vec![],
+        args: vec!["--name".to_string(), "".to_string(), " a b ".to_string()],
+        env: vec![("GREETING".to_string(), "a=b".to_string())],
+        ..input
+    };
+    let encoded = input.encode();
+    assert!(encoded.starts_with(
+        format!("token {TOKEN}\narg --name\narg \narg  a b \nenv GREETING=a=b\n\n").as_bytes()
+    ));
+    assert_eq!(Some(input), ScriptInput::decode(&encoded));
+    assert_eq!(None, ScriptInput::decode(b"actor Main"));
+}
+
+#[test]
+fn transcript_round_trip() {
+    let transcript = transcript(b"no newline at the end");
+    let raw = transcript.encode(TOKEN);
+    assert!(
+        raw.starts_with(
+            format!(
+                "{TOKEN} compiler status=0 elapsed_ms=1230 stdout=20 stderr=11 \
+             cpu_user_us=1100000 cpu_system_us=90000 peak_memory_bytes=250000000\n"
+            )
+            .as_bytes()
+        )
+    );
+    assert!(
+        raw.ends_with(format!("end{TOKEN} exit status=139 signal=11 elapsed_ms=20\n").as_bytes())
+    );
+    assert_eq!(transcript, Transcript::decode(&raw, TOKEN));
+}
+
+#[test]
+fn program_output_cannot_pass_for_frames() {
+    // the old delimiter, and frames with a token the program had to guess
+    let program = b"\xffcompiler status=0\nx exit status=0 elapsed_ms=1\n";
+    let transcript = transcript(program);
+    assert_eq!(
+        transcript,
+        Transcript::decode(&transcript.encode(TOKEN), TOKEN)
+    );
+}
+
+#[test]
+fn output_cut_short() {
+    let raw = transcript(b"loop").encode(TOKEN);
+
+    // killed while running
+    let end = raw.len() - format!("{TOKEN} exit status=139 signal=11 elapsed_ms=20\n").len();
+    let decoded = Transcript::decode(&raw[..end], TOKEN);
+    assert_eq!(b"loop", decoded.stdout.as_slice());
+    assert_eq!(None, decoded.exit);
+    assert_eq!(transcript(b"").compiler, decoded.compiler);
+
+    // stopped by the output limit in the middle of the compiler's output
+    let header = raw.iter().position(|b| *b == b'\n').unwrap() + 1;
+    let decoded = Transcript::decode(&raw[..header + 8], TOKEN);
+    let compiler = decoded.compiler.unwrap();
+    assert_eq!(b"Compiled", compiler.stdout.as_slice());
+    assert!(compiler.stderr.is_empty());
+    assert!(decoded.stdout.is_empty());
+
+    // killed while compiling
+    assert_eq!(Transcript::default(), Transcript::decode(b"", TOKEN));
+}
+
+#[test]
+fn unframed_output_is_the_programs() {
+    let decoded = Transcript::decode(b"Compiled with: LLVM\n\xff42\n", TOKEN);
+    assert_eq!(None, decoded.compiler);
+    assert_eq!(b"Compiled with: LLVM\n\xff42\n", decoded.stdout.as_slice());
+}
+
+#[test]
+fn timings_of_the_phases() {
+    let timings = transcript(b"").timings(Duration::from_millis(2000));
+    assert_eq!(Some(1230), timings.compile_ms);
+    assert_eq!(Some(20), timings.run_ms);
+    assert_eq!(750, timings.overhead_ms);
+    assert_eq!(2000, timings.total_ms);
+
+    // a sandbox that never got to compile
+    let timings = Transcript::default().timings(Duration::from_millis(300));
+    assert_eq!(None, timings.compile_ms);
+    assert_eq!(300, timings.overhead_ms);
+}
+
+#[test]
+fn decoding_as_output_arrives() {
+    let raw = transcript(b"tick\ntock\n").encode(TOKEN);
+    let mut decoder = Decoder::new(TOKEN);
+    let mut events = vec![];
+    // a byte at a time, splitting frames and the exit line
+    for byte in &raw {
+        events.extend(decoder.feed(&[*byte]));
+    }
+    let (decoded, rest) = decoder.finish();
+    assert!(rest.is_empty());
+    assert_eq!(transcript(b"tick\ntock\n"), decoded);
+
+    let compiled = events.remove(0);
+    assert_eq!(Event::Compiled(decoded.compiler.unwrap()), compiled);
+    let exited = events.pop().unwrap();
+    assert_eq!(Event::Exited(decoded.exit.unwrap()), exited);
+    let mut stdout = vec![];
+    for event in events {
+        let Event::Stdout(bytes) = event else {
+            panic!("{event:?} is not program output");
+        };
+        stdout.extend(bytes);
+    }
+    assert_eq!(b"tick\ntock\n", stdout.as_slice());
+}
+
+#[test]
+fn held_back_output_is_passed_on_at_the_end() {
+    let mut raw = transcript(b"").encode(TOKEN);
+    raw.truncate(raw.len() - format!("{TOKEN} exit status=139 signal=11 elapsed_ms=20\n").len());
+    let partial = &TOKEN.as_bytes()[..5];
+    raw.extend_from_slice(partial);
+    let mut decoder = Decoder::new(TOKEN);
+    let events = decoder.feed(&raw);
+    assert!(matches!(events.as_slice(), [Event::Compiled(_)]));
+    let (decoded, rest) = decoder.finish();
+    assert_eq!(vec![Event::Stdout(partial.to_vec())], rest);
+    assert_eq!(partial, decoded.stdout.as_slice());
+}
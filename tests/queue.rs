@@ -0,0 +1,251 @@
+use anyhow::Result;
+use pony_playground::api::serve;
+use pony_playground::{
+    Branch, Compilation, Config, Event, Exit, FakeSandbox, Playpen, QueueConfig, QueueFull,
+    RunResult, ScriptInput, Transcript, init_github_client,
+};
+use reqwest::{Client, StatusCode, header::RETRY_AFTER};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn playpen(sandbox: FakeSandbox, max_running: usize, max_queued: usize) -> Playpen {
+    let config = Config {
+        queue: QueueConfig {
+            max_running,
+            max_queued,
+            retry_after: Duration::from_secs(7),
+        },
+        ..Config::default()
+    };
+    Playpen::with_config(sandbox, config)
+}
+
+/// a run that compiled and printed `output`
+fn ok(sandbox: &FakeSandbox, output: &str) {
+    sandbox.push_transcript(
+        Transcript {
+            compiler: Some(Compilation::default()),
+            stdout: output.as_bytes().to_vec(),
+            exit: Some(Exit::default()),
+        },
+        |stdout| RunResult::exited(0, stdout, vec![]),
+    );
+}
+
+#[tokio::test]
+async fn runs_wait_for_a_free_slot() -> Result<()> {
+    let sandbox = FakeSandbox::new();
+    sandbox.delay(Duration::from_millis(300));
+    for _ in 0..3 {
+        ok(&sandbox, "");
+    }
+    let playpen = playpen(sandbox.clone(), 2, 10);
+
+    let runs: Vec<_> = (0..3)
+        .map(|i| {
+            let playpen = playpen.clone();
+            tokio::spawn(async move {
+                playpen
+                    .evaluate(&format!("client {i}"), Branch::Release, String::new())
+                    .await
+            })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let stats = playpen.stats().queue;
+    assert_eq!(2, stats.running);
+    assert_eq!(1, stats.queued);
+    assert_eq!(1, stats.clients);
+    assert_eq!(2, sandbox.requests().len());
+
+    for run in runs {
+        assert!(run.await??.result.success());
+    }
+    let stats = playpen.stats().queue;
+    assert_eq!(0, stats.running);
+    assert_eq!(0, stats.queued);
+    assert_eq!(3, sandbox.requests().len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn queue_is_fair_across_clients() -> Result<()> {
+    let sandbox = FakeSandbox::new();
+    sandbox.delay(Duration::from_millis(100));
+    for _ in 0..5 {
+        ok(&sandbox, "");
+    }
+    let playpen = playpen(sandbox.clone(), 1, 10);
+
+    // a submits four programs in a row, b one right after
+    let mut runs = vec![];
+    for (client, code) in [
+        ("a", "a1"),
+        ("a", "a2"),
+        ("a", "a3"),
+        ("a", "a4"),
+        ("b", "b1"),
+    ] {
+        let playpen = playpen.clone();
+        runs.push(tokio::spawn(async move {
+            playpen
+                .evaluate(client, Branch::Release, code.to_string())
+                .await
+        }));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let stats = playpen.stats().queue;
+    assert_eq!(4, stats.queued);
+    assert_eq!(2, stats.clients);
+
+    for run in runs {
+        run.await??;
+    }
+    let order: Vec<_> = sandbox
+        .requests()
+        .into_iter()
+        .map(|r| ScriptInput::decode(&r.input).unwrap().code)
+        .collect();
+    assert_eq!(vec!["a1", "a2", "b1", "a3", "a4"], order);
+    Ok(())
+}
+
+#[tokio::test]
+async fn full_queue_rejects_runs() -> Result<()> {
+    let sandbox = FakeSandbox::new();
+    sandbox.delay(Duration::from_millis(300));
+    for _ in 0..2 {
+        ok(&sandbox, "");
+    }
+    let playpen = playpen(sandbox.clone(), 1, 1);
+
+    let running = tokio::spawn({
+        let playpen = playpen.clone();
+        async move { playpen.evaluate("a", Branch::Release, String::new()).await }
+    });
+    let queued = tokio::spawn({
+        let playpen = playpen.clone();
+        async move { playpen.evaluate("b", Branch::Release, String::new()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let rejected = playpen
+        .evaluate("c", Branch::Release, String::new())
+        .await
+        .unwrap_err();
+    let full = rejected.downcast_ref::<QueueFull>().expect("queue full");
+    assert_eq!(Duration::from_secs(7), full.retry_after);
+
+    running.await??;
+    queued.await??;
+    assert_eq!(2, sandbox.requests().len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn abandoned_runs_leave_the_queue() -> Result<()> {
+    let sandbox = FakeSandbox::new();
+    sandbox.delay(Duration::from_millis(200));
+    for _ in 0..2 {
+        ok(&sandbox, "");
+    }
+    let playpen = playpen(sandbox.clone(), 1, 10);
+
+    let running = tokio::spawn({
+        let playpen = playpen.clone();
+        async move { playpen.evaluate("a", Branch::Release, String::new()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let abandoned = tokio::spawn({
+        let playpen = playpen.clone();
+        async move { playpen.evaluate("b", Branch::Release, String::new()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(1, playpen.stats().queue.queued);
+    abandoned.abort();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(0, playpen.stats().queue.queued);
+
+    running.await??;
+    assert_eq!(0, playpen.stats().queue.running);
+    // the slot is free again
+    playpen
+        .evaluate("c", Branch::Release, String::new())
+        .await?;
+    assert_eq!(2, sandbox.requests().len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn streamed_runs_are_told_their_position() -> Result<()> {
+    let sandbox = FakeSandbox::new();
+    sandbox.delay(Duration::from_millis(200));
+    for _ in 0..3 {
+        ok(&sandbox, "");
+    }
+    let playpen = playpen(sandbox.clone(), 1, 10);
+
+    let running = tokio::spawn({
+        let playpen = playpen.clone();
+        async move { playpen.evaluate("a", Branch::Release, String::new()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let first = tokio::spawn({
+        let playpen = playpen.clone();
+        async move { playpen.evaluate("b", Branch::Release, String::new()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let (events, mut received) = tokio::sync::mpsc::unbounded_channel();
+    let second = playpen.evaluate_streaming("run", "c", Branch::Release, String::new(), events);
+    second.await?;
+    running.await??;
+    first.await??;
+
+    let mut positions = vec![];
+    while let Ok(event) = received.try_recv() {
+        if let Event::Queued(position) = event {
+            positions.push(position);
+        }
+    }
+    assert_eq!(vec![1, 0], positions);
+    Ok(())
+}
+
+#[tokio::test]
+async fn full_queue_is_a_503() -> Result<()> {
+    let _ = env_logger::try_init();
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let sandbox = FakeSandbox::new();
+    sandbox.delay(Duration::from_millis(500));
+    ok(&sandbox, "42\n");
+    let port = portpicker::pick_unused_port().expect("No port available");
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let gh_client = init_github_client("FOO".to_string())?;
+    let handle = tokio::spawn(serve(addr, gh_client, playpen(sandbox, 1, 0)));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let url = format!("http://127.0.0.1:{port}/evaluate.json");
+    let first = tokio::spawn(
+        Client::new()
+            .post(&url)
+            .json(&json!({"code": "actor Main"}))
+            .send(),
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let res = Client::new()
+        .post(&url)
+        .json(&json!({"code": "actor Main"}))
+        .send()
+        .await?;
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+    assert_eq!("7", res.headers()[RETRY_AFTER]);
+
+    let res = first.await??;
+    assert_eq!(StatusCode::OK, res.status());
+    let payload: Value = res.json().await?;
+    assert_eq!("42\n", payload["stdout"]);
+
+    handle.abort();
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use anyhow::Result;
+use pony_playground::api::serve;
+use pony_playground::{
+    Config, FakeSandbox, Playpen, RateLimit, RateLimitConfig, RunResult, ServerConfig, Transcript,
+    init_github_client,
+};
+use reqwest::{
+    Client, RequestBuilder, StatusCode,
+    header::{CONTENT_TYPE, RETRY_AFTER},
+};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// start the server with a limit of two requests per route, one more every minute
+async fn start(
+    sandbox: FakeSandbox,
+    trusted_proxies: Vec<&str>,
+) -> Result<(u16, JoinHandle<Result<()>>)> {
+    let _ = env_logger::try_init();
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let limit = RateLimit {
+        burst: 2,
+        per_minute: 1,
+    };
+    let config = Config {
+        server: ServerConfig {
+            trusted_proxies: trusted_proxies
+                .into_iter()
+                .map(|ip| ip.parse().unwrap())
+                .collect(),
+            ..ServerConfig::default()
+        },
+        rate_limit: RateLimitConfig {
+            evaluate: limit,
+            compile: limit,
+            gist: limit,
+            jobs: limit,
+            batch: limit,
+        },
+        ..Config::default()
+    };
+    let port = portpicker::pick_unused_port().expect("No port available");
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let gh_client = init_github_client("FOO".to_string())?;
+    let playpen = Playpen::with_config(sandbox, config);
+    let handle = tokio::spawn(async move { serve(addr, gh_client, playpen).await });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    Ok((port, handle))
+}
+
+fn evaluate(port: u16) -> RequestBuilder {
+    Client::new()
+        .post(format!("http://127.0.0.1:{port}/evaluate.json"))
+        .json(&json!({"code": "actor Main"}))
+}
+
+fn scripted(runs: usize) -> FakeSandbox {
+    let sandbox = FakeSandbox::new();
+    for _ in 0..runs {
+        sandbox.push_transcript(Transcript::default(), |stdout| {
+            RunResult::exited(0, stdout, vec![])
+        });
+    }
+    sandbox
+}
+
+#[tokio::test]
+async fn too_many_requests() -> Result<()> {
+    let sandbox = scripted(2);
+    let (port, handle) = start(sandbox.clone(), vec![]).await?;
+
+    let res = evaluate(port).send().await?;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("2", res.headers()["ratelimit-limit"]);
+    assert_eq!("1", res.headers()["ratelimit-remaining"]);
+    let res = evaluate(port).send().await?;
+    assert_eq!(StatusCode::OK, res.status());
+    assert_eq!("0", res.headers()["ratelimit-remaining"]);
+
+    let res = evaluate(port).send().await?;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+    assert_eq!("0", res.headers()["ratelimit-remaining"]);
+    let retry_after: u64 = res.headers()[RETRY_AFTER].to_str()?.parse()?;
+    assert!((59..=60).contains(&retry_after), "{retry_after}");
+    let reset: u64 = res.headers()["ratelimit-reset"].to_str()?.parse()?;
+    assert!((119..=120).contains(&reset), "{reset}");
+    assert_eq!("application/problem+json", res.headers()[CONTENT_TYPE]);
+    let problem: Value = res.json().await?;
+    assert_eq!("rate_limited", problem["code"]);
+    assert_eq!(429, problem["status"]);
+    assert_eq!(json!(retry_after), problem["retry_after"]);
+    // rejected before reaching the sandbox
+    assert_eq!(2, sandbox.requests().len());
+
+    handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn routes_are_limited_separately() -> Result<()> {
+    let sandbox = scripted(2);
+    let (port, handle) = start(sandbox.clone(), vec![]).await?;
+
+    for _ in 0..2 {
+        assert_eq!(StatusCode::OK, evaluate(port).send().await?.status());
+    }
+    assert_eq!(
+        StatusCode::TOO_MANY_REQUESTS,
+        evaluate(port).send().await?.status()
+    );
+
+    // the gist route has a bucket of its own, and invalid requests count too,
+    // so this never gets to spend any GitHub quota
+    let gist = || {
+        Client::new()
+            .post(format!("http://127.0.0.1:{port}/gist.json"))
+            .json(&json!({"code": "actor Main"}))
+    };
+    for _ in 0..2 {
+        assert_eq!(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            gist().send().await?.status()
+        );
+    }
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, gist().send().await?.status());
+
+    // unlimited routes stay reachable
+    let res = Client::new()
+        .get(format!("http://127.0.0.1:{port}/stats.json"))
+        .send()
+        .await?;
+    assert_eq!(StatusCode::OK, res.status());
+
+    handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn forwarded_for_is_ignored_from_untrusted_peers() -> Result<()> {
+    let sandbox = scripted(2);
+    let (port, handle) = start(sandbox.clone(), vec![]).await?;
+
+    for client in ["192.0.2.1", "192.0.2.2"] {
+        let res = evaluate(port)
+            .header("X-Forwarded-For", client)
+            .send()
+            .await?;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    let res = evaluate(port)
+        .header("X-Forwarded-For", "192.0.2.3")
+        .send()
+        .await?;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+
+    handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn forwarded_for_from_trusted_proxy() -> Result<()> {
+    let sandbox = scripted(5);
+    let (port, handle) = start(sandbox.clone(), vec!["127.0.0.1", "10.0.0.1"]).await?;
+
+    for _ in 0..2 {
+        let res = evaluate(port)
+            .header("X-Forwarded-For", "192.0.2.1")
+            .send()
+            .await?;
+        assert_eq!(StatusCode::OK, res.status());
+    }
+    let res = evaluate(port)
+        .header("X-Forwarded-For", "192.0.2.1")
+        .send()
+        .await?;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+
+    // somebody else behind the same proxy
+    let res = evaluate(port)
+        .header("X-Forwarded-For", "192.0.2.2")
+        .send()
+        .await?;
+    assert_eq!(StatusCode::OK, res.status());
+
+    // addresses made up by the client are skipped, as are our own proxies
+    let res = evaluate(port)
+        .header("X-Forwarded-For", "203.0.113.7, 192.0.2.1, 10.0.0.1")
+        .send()
+        .await?;
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+    let res = evaluate(port)
+        .header("X-Forwarded-For", "192.0.2.1, 192.0.2.3")
+        .send()
+        .await?;
+    assert_eq!(StatusCode::OK, res.status());
+
+    handle.abort();
+    Ok(())
+}